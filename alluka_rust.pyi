@@ -29,11 +29,15 @@
 # CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
 # OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
 # OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+import types
 import typing
 from collections import abc as _collections
 
 import alluka
 
+_BasicContextT = typing.TypeVar("_BasicContextT", bound="BasicContext")
+_T = typing.TypeVar("_T")
+
 __author__: typing.Final[str]
 __ci__: typing.Final[str]
 __copyright__: typing.Final[str]
@@ -45,14 +49,137 @@ __license__: typing.Final[str]
 __url__: typing.Final[str]
 __version__: typing.Final[str]
 
+class DependencyTimeoutError(TimeoutError): ...
+
+class InjectedDescriptor(alluka.InjectedDescriptor[_T]):
+    on_error: typing.Any
+    on_error_callback: typing.Optional[_collections.Callable[..., typing.Any]]
+    on_error_types: tuple[type[BaseException], ...]
+    retry: typing.Optional[RetryPolicy]
+    timeout: typing.Optional[float]
+
+    def __init__(
+        self,
+        *,
+        callback: typing.Optional[_collections.Callable[..., typing.Any]] = None,
+        type: typing.Optional[typing.Any] = None,
+        on_error: typing.Any = alluka.abc.UNDEFINED,
+        on_error_callback: typing.Optional[
+            _collections.Callable[..., typing.Any]
+        ] = None,
+        on_error_types: tuple[type[BaseException], ...] = (Exception,),
+        retry: typing.Optional[RetryPolicy] = None,
+        timeout: typing.Optional[float] = None,
+    ) -> None: ...
+
+class RetryPolicy:
+    @property
+    def attempts(self) -> int: ...
+    @property
+    def backoff(self) -> float: ...
+    @property
+    def on(self) -> tuple[type[BaseException], ...]: ...
+    def __init__(
+        self,
+        attempts: int,
+        backoff: float = 0.0,
+        on: typing.Optional[tuple[type[BaseException], ...]] = None,
+    ) -> None: ...
+
+def current_context() -> typing.Optional[alluka.abc.Context]: ...
+def inject(
+    *,
+    callback: typing.Optional[_collections.Callable[..., typing.Any]] = None,
+    type: typing.Optional[typing.Any] = None,
+    on_error: typing.Any = alluka.abc.UNDEFINED,
+    on_error_callback: typing.Optional[_collections.Callable[..., typing.Any]] = None,
+    on_error_types: tuple[type[BaseException], ...] = (Exception,),
+    retry: typing.Optional[RetryPolicy] = None,
+    timeout: typing.Optional[float] = None,
+) -> typing.Any: ...
 def patch_alluka() -> None: ...
 
 class Client(alluka.abc.Client):
     __slots__: _collections.Iterable[str]
 
-    def __init__(self, *, introspect_annotations: bool = True) -> None: ...
+    def __init__(
+        self,
+        *,
+        introspect_annotations: bool = True,
+        parent: typing.Optional[alluka.abc.Client] = None,
+        resolution_timeout: typing.Optional[float] = None,
+        sync_async_bridge: typing.Optional[typing.Literal["run", "thread"]] = None,
+    ) -> None: ...
+    def create_child(self) -> Client: ...
+    def call_with_overrides(
+        self,
+        overrides: _collections.Mapping[type[typing.Any], typing.Any],
+        callback: _collections.Callable[..., _T],
+        /,
+        *args: typing.Any,
+        **kwargs: typing.Any,
+    ) -> _T: ...
+    async def call_with_async_overrides(
+        self,
+        overrides: _collections.Mapping[type[typing.Any], typing.Any],
+        callback: _collections.Callable[
+            ..., typing.Union[_collections.Coroutine[typing.Any, typing.Any, _T], _T]
+        ],
+        /,
+        *args: typing.Any,
+        **kwargs: typing.Any,
+    ) -> _T: ...
+    def override_types(
+        self,
+        types: typing.Optional[
+            _collections.Mapping[type[typing.Any], typing.Any]
+        ] = None,
+        /,
+        *,
+        callbacks: typing.Optional[
+            _collections.Mapping[
+                _collections.Callable[..., typing.Any],
+                _collections.Callable[..., typing.Any],
+            ]
+        ] = None,
+    ) -> Overrides: ...
+
+class Overrides:
+    def __enter__(self) -> None: ...
+    def __exit__(
+        self,
+        exc_type: typing.Optional[type[BaseException]],
+        exc_value: typing.Optional[BaseException],
+        traceback: typing.Optional[types.TracebackType],
+    ) -> None: ...
+    async def __aenter__(self) -> None: ...
+    async def __aexit__(
+        self,
+        exc_type: typing.Optional[type[BaseException]],
+        exc_value: typing.Optional[BaseException],
+        traceback: typing.Optional[types.TracebackType],
+    ) -> None: ...
 
 class BasicContext(alluka.abc.Context):
     __slots__: _collections.Iterable[str]
 
     def __init__(self, client: Client, /) -> None: ...
+    def child(self) -> BasicContext: ...
+    def set_type_dependency(
+        self: _BasicContextT, type_: type[_T], value: _T, /
+    ) -> _BasicContextT: ...
+    def remove_type_dependency(
+        self: _BasicContextT, type_: type[typing.Any], /
+    ) -> _BasicContextT: ...
+    def set_callback_override(
+        self: _BasicContextT,
+        callback: _collections.Callable[..., _T],
+        override: _collections.Callable[..., _T],
+        /,
+    ) -> _BasicContextT: ...
+    def get_callback_override(
+        self, callback: _collections.Callable[..., _T], /
+    ) -> typing.Optional[_collections.Callable[..., _T]]: ...
+    def remove_callback_override(
+        self: _BasicContextT, callback: _collections.Callable[..., typing.Any], /
+    ) -> _BasicContextT: ...
@@ -98,6 +98,8 @@ def test(session: nox.Session) -> None:
             "./dev-requirements.txt",
             "-r",
             str(target / "dev-requirements/tests.txt"),
+            # Used to test this project's own anyio support.
+            "trio>=0.20",
         )
         shutil.copyfile("./conftest.py", str(target / "conftest.py"))
         session.run("maturin", "develop")
@@ -109,3 +111,5 @@ def test(session: nox.Session) -> None:
             "--import-mode",
             "importlib",
         )
+
+    session.run("pytest", "./tests", "-n", "auto")
@@ -0,0 +1,227 @@
+// BSD 3-Clause License
+//
+// Copyright (c) 2022, Lucina
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+//   list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+//   this list of conditions and the following disclaimer in the documentation
+//   and/or other materials provided with the distribution.
+//
+// * Neither the name of the copyright holder nor the names of its contributors
+//   may be used to endorse or promote products derived from this software
+//   without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+use std::future::Future;
+use std::sync::OnceLock;
+use std::task::Poll;
+
+use pyo3::exceptions::PyBaseException;
+use pyo3::pycell::PyCell;
+use pyo3::{Py, PyAny, PyErr, PyObject, PyResult, Python, ToPyObject};
+use tokio::sync::watch;
+
+use crate::client::Context;
+
+pyo3::create_exception!(
+    alluka_rust,
+    CallCancelledError,
+    PyBaseException,
+    "Error used to stop the Rust side of an async DI call which was cancelled."
+);
+
+static GENERATOR_TYPES: OnceLock<PyObject> = OnceLock::new();
+
+// Only generators and async generators are torn down on cancellation.
+fn is_resource(py: Python, value: &PyAny) -> PyResult<bool> {
+    let types = GENERATOR_TYPES.get_or_try_init(|| {
+        let types = py.import("types")?;
+        Ok::<_, PyErr>((types.getattr("GeneratorType")?, types.getattr("AsyncGeneratorType")?).to_object(py))
+    })?;
+    value.is_instance(types.as_ref(py))
+}
+
+// A resource freshly resolved by the call which is cached once it's passed to
+// the callback.
+struct Resource {
+    ctx: Context,
+    key: PyObject,
+    value: PyObject,
+}
+
+/// Tracks the cancellation of an async DI call.
+///
+/// This is cancelled from Python when the task awaiting the call is cancelled
+/// and passes that cancellation on to the anyio cancel scopes of in-flight
+/// dependency coroutines and to the Rust future driving the call.
+#[pyo3::pyclass]
+pub struct CancelToken {
+    cancelled: bool,
+    resources: Vec<Resource>,
+    scopes: Vec<PyObject>,
+    sender: watch::Sender<bool>,
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        Self {
+            cancelled: false,
+            resources: Vec::new(),
+            scopes: Vec::new(),
+            sender: watch::channel(false).0,
+        }
+    }
+}
+
+impl CancelToken {
+    fn cancelled(&self) -> impl Future<Output = ()> + Send + 'static {
+        let already_cancelled = self.cancelled;
+        let mut receiver = self.sender.subscribe();
+        async move {
+            if already_cancelled {
+                return;
+            }
+
+            loop {
+                let cancelled = *receiver.borrow();
+                if cancelled {
+                    return;
+                }
+
+                if receiver.changed().await.is_err() {
+                    // The token was dropped without being cancelled.
+                    return std::future::pending().await;
+                }
+            }
+        }
+    }
+
+    /// Whether this error should be treated as the call being cancelled rather
+    /// than as a dependency failing.
+    ///
+    /// Once the token's cancelled any error may just be the fallout of the
+    /// cancellation so none of them are retried or given a fallback.
+    pub fn is_cancellation(&self, py: Python, err: &PyErr) -> bool {
+        self.cancelled || err.is_instance_of::<CallCancelledError>(py)
+    }
+
+    /// Take ownership of a dependency result this call freshly produced.
+    ///
+    /// Generators are only cached once they're passed to the callback so they
+    /// can be torn down without leaving a closed resource in the cache if
+    /// the call is cancelled first, while other values are cached straight
+    /// away. Values which came from the cache must never be tracked as
+    /// they're shared.
+    pub fn track(slf: &Py<Self>, py: Python, ctx: &Context, key: &PyAny, value: &PyAny) -> PyResult<()> {
+        if !is_resource(py, value)? {
+            return ctx.cache_result(py, key, value.to_object(py));
+        }
+
+        slf.borrow_mut(py).resources.push(Resource {
+            ctx: ctx.clone_ref(py),
+            key: key.to_object(py),
+            value: value.to_object(py),
+        });
+        Ok(())
+    }
+
+    /// Hand tracked resources over to the callback, caching them.
+    pub fn release<'a>(slf: &Py<Self>, py: Python, values: impl IntoIterator<Item = &'a PyObject>) -> PyResult<()> {
+        let mut borrow = slf.borrow_mut(py);
+        let released = values
+            .into_iter()
+            .filter_map(|value| {
+                let index = borrow.resources.iter().position(|resource| resource.value.is(value))?;
+                Some(borrow.resources.swap_remove(index))
+            })
+            .collect::<Vec<_>>();
+        // Caching may call back into Python.
+        drop(borrow);
+
+        for resource in released {
+            resource.ctx.cache_result(py, resource.key.as_ref(py), resource.value)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[pyo3::pymethods]
+impl CancelToken {
+    #[getter]
+    fn is_cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    fn register(&mut self, scope: &PyAny) -> PyResult<()> {
+        if self.cancelled {
+            scope.call_method0("cancel")?;
+        } else {
+            self.scopes.push(scope.into());
+        }
+
+        Ok(())
+    }
+
+    fn unregister(&mut self, scope: &PyAny) {
+        self.scopes.retain(|value| !value.is(scope));
+    }
+
+    fn cancel(slf: &PyCell<Self>, py: Python) -> PyResult<Vec<PyObject>> {
+        let mut borrow = slf.borrow_mut();
+        if borrow.cancelled {
+            return Ok(vec![]);
+        }
+
+        borrow.cancelled = true;
+        borrow.sender.send_replace(true);
+        let scopes = std::mem::take(&mut borrow.scopes);
+        let resources = std::mem::take(&mut borrow.resources);
+        // Cancelling a scope may call back into this token.
+        drop(borrow);
+
+        for scope in scopes {
+            scope.call_method0(py, "cancel")?;
+        }
+
+        Ok(resources.into_iter().map(|resource| resource.value).collect())
+    }
+}
+
+pub fn cancelled_error() -> PyErr {
+    CallCancelledError::new_err("The DI call was cancelled")
+}
+
+/// Await a future, stopping early with an error if the token is cancelled.
+pub async fn until_cancelled<T>(token: &Py<CancelToken>, fut: impl Future<Output = PyResult<T>>) -> PyResult<T> {
+    let mut fut = Box::pin(fut);
+    let mut cancelled = Box::pin(Python::with_gil(|py| token.borrow(py).cancelled()));
+    std::future::poll_fn(|cx| {
+        if let Poll::Ready(result) = fut.as_mut().poll(cx) {
+            return Poll::Ready(result);
+        }
+
+        if cancelled.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Err(cancelled_error()));
+        }
+
+        Poll::Pending
+    })
+    .await
+}
@@ -28,30 +28,329 @@
 // CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
 // ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
 // POSSIBILITY OF SUCH DAMAGE.
-use std::collections::hash_map::RawEntryMut;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::convert::AsRef;
 use std::future::Future;
-use std::sync::{Arc, OnceLock};
-
-use pyo3::exceptions::PyKeyError;
-use pyo3::pycell::PyRef;
-use pyo3::types::{IntoPyDict, PyDict, PyTuple};
-use pyo3::{IntoPy, Py, PyAny, PyErr, PyObject, PyRefMut, PyResult, Python, ToPyObject};
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError, RwLock, Weak};
+
+use pyo3::exceptions::{PyKeyError, PyRuntimeError, PyTypeError, PyValueError};
+use pyo3::pycell::{PyCell, PyRef};
+use pyo3::types::{IntoPyDict, PyCFunction, PyDict, PyMapping, PyTuple};
+use pyo3::{AsPyPointer, IntoPy, Py, PyAny, PyErr, PyObject, PyRefMut, PyResult, Python, ToPyObject};
 use pyo3_anyio::tokio::{await_py1, fut_into_coro};
-use tokio::sync::RwLock;
+use tokio::sync::OnceCell;
 
-use crate::types::{Injected, InjectedTuple};
-use crate::visitor::{Callback, ParameterVisitor};
+use crate::cancel::{until_cancelled, CancelToken};
+use crate::driver::{CallDriver, Ready};
+use crate::types::{DependencyTimeoutError, Injected, InjectedTuple, Resolved};
+use crate::visitor::{is_coroutine_function, unwrap_function, Callback, Fingerprint, ParameterVisitor};
 
 
 pyo3::import_exception!(alluka._errors, AsyncOnlyError);
 
-type DescriptorMap = Arc<RwLock<HashMap<isize, Arc<Box<[InjectedTuple]>>>>>;
+type DescriptorMap = Arc<RwLock<DescriptorCache>>;
+
+/// The injected parameters of a callback.
+pub struct Descriptors {
+    entries: Box<[InjectedTuple]>,
+    // Whether the callback is a coroutine function.
+    is_async: bool,
+    // Cached keyword names tuple used when calling the callback with vectorcall.
+    names: Py<PyTuple>,
+    // Whether the callback or (transitively) one of its callback dependencies is
+    // a coroutine function when no callback overrides are in scope. This is
+    // lazily set as it relies on the descriptors of the callback's dependencies.
+    requires_async: OnceLock<bool>,
+}
+
+impl Descriptors {
+    fn new(py: Python, entries: Vec<InjectedTuple>, is_async: bool) -> Self {
+        let names = PyTuple::new(py, entries.iter().map(|(name, _)| name)).into_py(py);
+        Self {
+            entries: entries.into_boxed_slice(),
+            is_async,
+            names,
+            requires_async: OnceLock::new(),
+        }
+    }
+}
+
+impl Descriptors {
+    /// Whether the callback is a coroutine function.
+    pub fn is_async(&self) -> bool {
+        self.is_async
+    }
+}
+
+impl Deref for Descriptors {
+    type Target = [InjectedTuple];
+
+    fn deref(&self) -> &Self::Target {
+        &self.entries
+    }
+}
+
+#[cfg(all(Py_3_9, not(any(PyPy, Py_LIMITED_API))))]
+fn call_with_names<'p>(
+    py: Python<'p>,
+    callback: &PyAny,
+    args: &PyTuple,
+    names: &PyTuple,
+    values: &[&PyAny],
+) -> PyResult<&'p PyAny> {
+    let all_args = args
+        .iter()
+        .chain(values.iter().copied())
+        .map(AsPyPointer::as_ptr)
+        .collect::<Vec<_>>();
+    // SAFETY: every argument is borrowed for the length of the call and the
+    // names tuple holds exactly one name per value.
+    unsafe {
+        py.from_owned_ptr_or_err(pyo3::ffi::PyObject_Vectorcall(
+            callback.as_ptr(),
+            all_args.as_ptr(),
+            args.len(),
+            names.as_ptr(),
+        ))
+    }
+}
+
+// Vectorcall isn't publicly exposed before 3.9 or under the limited API.
+#[cfg(not(all(Py_3_9, not(any(PyPy, Py_LIMITED_API)))))]
+fn call_with_names<'p>(
+    py: Python<'p>,
+    callback: &'p PyAny,
+    args: &PyTuple,
+    names: &PyTuple,
+    values: &[&PyAny],
+) -> PyResult<&'p PyAny> {
+    let kwargs = names.iter().zip(values.iter().copied()).into_py_dict(py);
+    callback.call(args, Some(kwargs))
+}
+// The error is stored as the exception object since PyErr can't be cloned.
+pub type InFlight = Arc<OnceCell<Result<PyObject, PyObject>>>;
+// [None] marks an entry which was removed from a child client and shouldn't
+// fall back to the parent client.
+type Registry = HashMap<isize, Option<PyObject>>;
+
+// How many signatures are cached per code object. This bounds the cache for
+// closures which are defined with a new default or annotation each time.
+const FUNCTION_VARIANTS_LIMIT: usize = 16;
+// How many callbacks are remembered by identity before the memo's cleared.
+const IDENTITIES_LIMIT: usize = 1024;
+
+/// Descriptors cached for one signature of a code object.
+struct FunctionVariant {
+    descriptors: Arc<Descriptors>,
+    fingerprint: Fingerprint,
+    // The address (and whether it was bound) and a weak reference of the last
+    // function which matched this variant.
+    function: ((usize, bool), PyObject),
+}
+
+/// The descriptors cached for each callback.
+///
+/// Plain functions are grouped by code object so closures with the same code
+/// and signature share descriptors, while other callbacks are keyed by hash.
+#[derive(Default)]
+struct DescriptorCache {
+    callbacks: HashMap<isize, Arc<Descriptors>>,
+    // Callbacks which were already looked up, keyed by address alongside a
+    // weak reference which confirms the address still belongs to them. This
+    // lets repeat calls skip unwrapping and fingerprinting functions.
+    identities: HashMap<usize, (PyObject, Arc<Descriptors>)>,
+    // Keyed by the address of the code object, which is kept alive by the entry.
+    functions: HashMap<usize, (PyObject, Vec<FunctionVariant>)>,
+}
+
+/// Copy-on-write registry which can be read and written from any thread.
+///
+/// Readers take a cheap snapshot of the current state while writers copy the
+/// map (only if a snapshot is still alive) and publish it. The lock is only
+/// ever held to swap the [Arc] and never while calling into Python.
+#[derive(Default)]
+struct SharedRegistry(Mutex<Arc<Registry>>);
+
+impl SharedRegistry {
+    fn lock(&self) -> MutexGuard<Arc<Registry>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn snapshot(&self) -> Arc<Registry> {
+        self.lock().clone()
+    }
+
+    fn get(&self, py: Python, key: &isize) -> Option<Option<PyObject>> {
+        self.snapshot()
+            .get(key)
+            .map(|value| value.as_ref().map(|value| value.clone_ref(py)))
+    }
+
+    // The callback should return any entries it removes or replaces so they're
+    // only dropped after the lock's released, as dropping them may call back
+    // into this registry through `__del__`.
+    fn update<T>(&self, callback: impl FnOnce(&mut Registry) -> T) -> T {
+        let mut registry = self.lock();
+        let result = callback(Arc::make_mut(&mut registry));
+        GENERATION.fetch_add(1, Ordering::Release);
+        drop(registry);
+        result
+    }
+}
+
+// Bumped whenever any client's registries change to invalidate binding plans.
+//
+// This is global rather than per-client as a client's plans also depend on
+// the registries of its parents, which don't know about their children.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Type dependencies pre-resolved from a client's registries for a descriptor
+/// set.
+///
+/// Entries are [None] for callback dependencies and types which couldn't be
+/// resolved ahead of time.
+struct BindingPlan {
+    // Held weakly so evicted descriptor sets are freed. This also keeps the
+    // address the plan's keyed by from being reused while the plan's cached.
+    descriptors: Weak<Descriptors>,
+    generation: u64,
+    values: Box<[Option<PyObject>]>,
+}
+
+// The same bound asyncio uses for its own cache of coroutine types.
+const COROUTINE_TYPES_LIMIT: usize = 100;
+static COROUTINE_TYPES: OnceLock<Mutex<HashMap<usize, PyObject>>> = OnceLock::new();
+
+// Types which asyncio.iscoroutine accepted are cached (and kept alive so their
+// ids can't be reused). Like asyncio, negative results aren't cached as a type
+// may later be registered as a coroutine through `Coroutine.register`.
+pub fn is_coroutine(py: Python, value: &PyAny) -> PyResult<bool> {
+    let type_ = value.get_type();
+    let types = COROUTINE_TYPES.get_or_init(Mutex::default);
+    let key = type_.as_ptr() as usize;
+    if types.lock().unwrap_or_else(PoisonError::into_inner).contains_key(&key) {
+        return Ok(true);
+    }
+
+    let result = import_asyncio(py)?.call_method1("iscoroutine", (value,))?.is_true()?;
+    if result {
+        let mut types = types.lock().unwrap_or_else(PoisonError::into_inner);
+        if types.len() < COROUTINE_TYPES_LIMIT {
+            types.entry(key).or_insert_with(|| type_.into());
+        }
+    }
+
+    Ok(result)
+}
 
 static ALLUKA: OnceLock<PyObject> = OnceLock::new();
 static ASYNCIO: OnceLock<PyObject> = OnceLock::new();
+static CONTEXTVARS: OnceLock<PyObject> = OnceLock::new();
+static CURRENT_CONTEXT: OnceLock<PyObject> = OnceLock::new();
 static SELF_INJECTING: OnceLock<PyObject> = OnceLock::new();
+static SHIMS: OnceLock<PyObject> = OnceLock::new();
+static WEAKREF: OnceLock<PyObject> = OnceLock::new();
+
+// Python helpers used to drive async calls and the sync async bridge.
+const SHIMS_CODE: &str = r#"
+async def cancellable(token, coro):
+    try:
+        return await coro
+
+    except get_cancelled_exc_class():
+        # Tear down any dependencies which were resolved but never passed to a callback.
+        for resource in token.cancel():
+            if isgenerator(resource):
+                resource.close()
+
+            elif isasyncgen(resource):
+                with CancelScope(shield=True):
+                    await resource.aclose()
+
+        raise
+
+
+async def with_timeout(parent_token, timeout, token, coro, message):
+    with move_on_after(timeout) as scope:
+        parent_token.register(scope)
+        try:
+            return await cancellable(token, coro)
+
+        finally:
+            parent_token.unregister(scope)
+
+    if parent_token.is_cancelled:
+        raise get_cancelled_exc_class()
+
+    raise DependencyTimeoutError(message)
+
+
+async def scoped(token, awaitable):
+    with CancelScope() as scope:
+        token.register(scope)
+        try:
+            return await awaitable
+
+        finally:
+            token.unregister(scope)
+
+    # Only reached if the scope caught its own cancellation.
+    raise get_cancelled_exc_class()
+
+
+class LoopThread:
+    __slots__ = ("_lock", "_loop", "_thread")
+
+    def __init__(self):
+        self._lock = Lock()
+        self._loop = None
+        self._thread = None
+
+    def run(self, coro):
+        with self._lock:
+            if self._loop is None:
+                self._loop = new_event_loop()
+                self._thread = Thread(target=self._loop.run_forever, name="alluka-rust-bridge", daemon=True)
+                self._thread.start()
+
+        if current_thread() is self._thread:
+            coro.close()
+            raise RuntimeError("Cannot block on the sync async bridge's event loop from within itself")
+
+        return run_coroutine_threadsafe(coro, self._loop).result()
+"#;
+
+fn import_shim<'p>(py: Python<'p>, name: &str) -> PyResult<&'p PyAny> {
+    SHIMS
+        .get_or_try_init(|| {
+            let anyio = py.import("anyio")?;
+            let asyncio = import_asyncio(py)?;
+            let inspect = py.import("inspect")?;
+            let threading = py.import("threading")?;
+            let globals_ = [
+                ("CancelScope", anyio.getattr("CancelScope")?),
+                ("DependencyTimeoutError", py.get_type::<DependencyTimeoutError>()),
+                ("Lock", threading.getattr("Lock")?),
+                ("Thread", threading.getattr("Thread")?),
+                ("current_thread", threading.getattr("current_thread")?),
+                ("get_cancelled_exc_class", anyio.getattr("get_cancelled_exc_class")?),
+                ("isasyncgen", inspect.getattr("isasyncgen")?),
+                ("isgenerator", inspect.getattr("isgenerator")?),
+                ("move_on_after", anyio.getattr("move_on_after")?),
+                ("new_event_loop", asyncio.getattr("new_event_loop")?),
+                ("run_coroutine_threadsafe", asyncio.getattr("run_coroutine_threadsafe")?),
+            ]
+            .into_py_dict(py);
+            py.run(SHIMS_CODE, Some(globals_), None)?;
+            Ok::<_, PyErr>(globals_.to_object(py))
+        })?
+        .as_ref(py)
+        .get_item(name)
+}
 
 fn import_alluka(py: Python) -> PyResult<&PyAny> {
     ALLUKA
@@ -65,230 +364,820 @@ fn import_asyncio(py: Python) -> PyResult<&PyAny> {
         .map(|value| value.as_ref(py))
 }
 
+fn import_contextvars(py: Python) -> PyResult<&PyAny> {
+    CONTEXTVARS
+        .get_or_try_init(|| Ok(py.import("contextvars")?.to_object(py)))
+        .map(|value| value.as_ref(py))
+}
+
+pub fn current_context_var(py: Python) -> PyResult<&PyAny> {
+    CURRENT_CONTEXT
+        .get_or_try_init(|| {
+            let kwargs = [("default", py.None())].into_py_dict(py);
+            Ok(import_contextvars(py)?
+                .getattr("ContextVar")?
+                .call(("alluka_rust.current_context",), Some(kwargs))?
+                .to_object(py))
+        })
+        .map(|value| value.as_ref(py))
+}
+
 fn import_self_injecting(py: Python) -> PyResult<&PyAny> {
     SELF_INJECTING
         .get_or_try_init(|| Ok(py.import("alluka._self_injecting")?.to_object(py)))
         .map(|value| value.as_ref(py))
 }
 
+fn import_weakref(py: Python) -> PyResult<&PyAny> {
+    WEAKREF
+        .get_or_try_init(|| Ok(py.import("weakref")?.getattr("ref")?.to_object(py)))
+        .map(|value| value.as_ref(py))
+}
+
+/// How sync DI calls drive callbacks which require an event loop.
+enum SyncAsyncBridge {
+    /// Run the call with `asyncio.run`.
+    Run,
+    /// Run the call on a dedicated event loop thread which is shared with child
+    /// clients.
+    Thread(PyObject),
+}
+
+impl SyncAsyncBridge {
+    fn new(py: Python, name: &str, loop_thread: &PyAny) -> PyResult<Self> {
+        match name {
+            "run" => Ok(Self::Run),
+            "thread" => Ok(Self::Thread(loop_thread.call0()?.to_object(py))),
+            _ => Err(PyValueError::new_err(format!(
+                "Invalid sync_async_bridge {name:?}, expected \"run\" or \"thread\""
+            ))),
+        }
+    }
+
+    fn clone_ref(&self, py: Python) -> Self {
+        match self {
+            Self::Run => Self::Run,
+            Self::Thread(loop_thread) => Self::Thread(loop_thread.clone_ref(py)),
+        }
+    }
+
+    fn run<'p>(&self, py: Python<'p>, coro: &'p PyAny) -> PyResult<&'p PyAny> {
+        match self {
+            Self::Run => import_asyncio(py)?.call_method1("run", (coro,)).or_else(|err| {
+                // asyncio.run refuses to run within a running event loop
+                // without closing the coroutine (a no-op if it already ran).
+                coro.call_method0("close")?;
+                Err(err)
+            }),
+            Self::Thread(loop_thread) => loop_thread.as_ref(py).call_method1("run", (coro,)),
+        }
+    }
+}
+
 #[pyo3::pyclass(subclass)]
 pub struct Client {
-    callback_overrides: HashMap<isize, PyObject>,
+    callback_overrides: SharedRegistry,
+    cancellable: PyObject,
     descriptors: DescriptorMap,
     introspect_annotations: bool,
-    maybe_await: PyObject,
-    type_dependencies: HashMap<isize, PyObject>,
+    parent: Option<PyObject>,
+    // Keyed by the address of the descriptor set. Plans for descriptor sets
+    // which have been evicted are pruned when new plans are added.
+    plans: Mutex<HashMap<usize, Arc<BindingPlan>>>,
+    // Whether binding plans can be used, which is only the case when every
+    // parent is a native client (so registry changes bump the generation).
+    plannable: bool,
+    pub resolution_timeout: Option<f64>,
+    scoped: PyObject,
+    sync_async_bridge: Option<SyncAsyncBridge>,
+    type_dependencies: SharedRegistry,
+    with_timeout: PyObject,
 }
 
 
-async fn build_descriptors_async(
-    all_descriptors: DescriptorMap,
-    key: isize,
-    callback: PyObject,
-) -> PyResult<Arc<Box<[InjectedTuple]>>> {
-    // Avoid a write lock if we already have the descriptors.
-    if let Some(descriptors) = all_descriptors.read().await.get(&key).map(Arc::clone) {
-        return Ok(descriptors);
+impl Client {
+    fn new_descriptors(py: Python, callback: &PyAny) -> PyResult<Arc<Descriptors>> {
+        Ok(Arc::new(Descriptors::new(
+            py,
+            Callback::new(py, callback)?.accept::<ParameterVisitor>(py)?,
+            is_coroutine_function(py, callback)?,
+        )))
     }
 
-    let mut descriptors = all_descriptors.write().await;
-    let entry = descriptors.raw_entry_mut().from_key(&key);
-    Ok(match entry {
-        RawEntryMut::Occupied(entry) => entry.into_key_value().1.clone(),
-        RawEntryMut::Vacant(entry) => {
-            let descriptors =
-                Python::with_gil(|py| Callback::new(py, callback.as_ref(py))?.accept::<ParameterVisitor>(py))?;
-            entry.insert(key, Arc::new(Box::from(descriptors))).1.clone()
+    // This uses a sync lock which is never held across an await or while calling
+    // into Python (including dropping Python objects) so sync and async DI calls
+    // can nest freely, including within a tokio runtime.
+    //
+    // Concurrent first calls may both build the descriptors but only the first to
+    // finish is published and used.
+    pub fn build_descriptors(&self, py: Python, callback: &PyAny) -> PyResult<Arc<Descriptors>> {
+        let identity = callback.as_ptr() as usize;
+        let known = self
+            .descriptors
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .identities
+            .get(&identity)
+            .map(|(reference, descriptors)| (reference.clone_ref(py), descriptors.clone()));
+
+        if let Some((reference, descriptors)) = known {
+            // The address may belong to a new callback if the last one died.
+            if reference.as_ref(py).call0()?.is(callback) {
+                return Ok(descriptors);
+            }
         }
-    })
-}
 
+        let descriptors = self.lookup_descriptors(py, callback)?;
+        let reference = match import_weakref(py)?.call1((callback,)) {
+            Ok(reference) => reference.to_object(py),
+            // Not every callback can be weakly referenced.
+            Err(err) if err.is_instance_of::<PyTypeError>(py) => return Ok(descriptors),
+            Err(err) => return Err(err),
+        };
+
+        let mut cache = self.descriptors.write().unwrap_or_else(PoisonError::into_inner);
+        let cleared = if cache.identities.len() >= IDENTITIES_LIMIT {
+            std::mem::take(&mut cache.identities)
+        } else {
+            HashMap::new()
+        };
+        let replaced = cache.identities.insert(identity, (reference, descriptors.clone()));
+        // Anything holding Python objects is dropped after the lock's released.
+        drop(cache);
+        drop(cleared);
+        drop(replaced);
+        Ok(descriptors)
+    }
+
+    fn lookup_descriptors(&self, py: Python, callback: &PyAny) -> PyResult<Arc<Descriptors>> {
+        if let Some((function, is_method)) = unwrap_function(py, callback)? {
+            return self.build_function_descriptors(py, callback, function, is_method);
+        }
 
-impl Client {
-    fn build_descriptors(&self, py: Python, callback: &PyAny) -> PyResult<Arc<Box<[InjectedTuple]>>> {
         let key = callback.hash()?;
-        // Avoid a write lock if we already have the descriptors.
-        if let Some(descriptors) = self.descriptors.blocking_read().get(&key).map(Arc::clone) {
+        if let Some(descriptors) = self
+            .descriptors
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .callbacks
+            .get(&key)
+        {
+            return Ok(descriptors.clone());
+        }
+
+        let built = Self::new_descriptors(py, callback)?;
+        let mut cache = self.descriptors.write().unwrap_or_else(PoisonError::into_inner);
+        let published = cache.callbacks.entry(key).or_insert_with(|| built.clone()).clone();
+        drop(cache);
+        Ok(published)
+    }
+
+    fn build_function_descriptors(
+        &self,
+        py: Python,
+        callback: &PyAny,
+        function: &PyAny,
+        is_method: bool,
+    ) -> PyResult<Arc<Descriptors>> {
+        let code = function.getattr("__code__")?;
+        let code_key = code.as_ptr() as usize;
+        let function_key = (function.as_ptr() as usize, is_method);
+
+        // Repeat calls with the same function are matched by identity, only
+        // falling back to its fingerprint if this is a new function.
+        let last = self
+            .descriptors
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .functions
+            .get(&code_key)
+            .and_then(|(_, variants)| variants.iter().find(|variant| variant.function.0 == function_key))
+            .map(|variant| (variant.function.1.clone_ref(py), variant.descriptors.clone()));
+
+        if let Some((reference, descriptors)) = last {
+            // The address may belong to a new function if the last one died.
+            if reference.as_ref(py).call0()?.is(function) {
+                return Ok(descriptors);
+            }
+        }
+
+        let fingerprint = Fingerprint::new(py, function, is_method)?;
+        let reference = import_weakref(py)?.call1((function,))?.to_object(py);
+        let mut function = (function_key, reference);
+        let mut cache = self.descriptors.write().unwrap_or_else(PoisonError::into_inner);
+        let found = cache.functions.get_mut(&code_key).and_then(|(_, variants)| {
+            let variant = variants.iter_mut().find(|variant| variant.fingerprint == fingerprint)?;
+            std::mem::swap(&mut variant.function, &mut function);
+            Some(variant.descriptors.clone())
+        });
+        drop(cache);
+        if let Some(descriptors) = found {
             return Ok(descriptors);
         }
 
-        let mut descriptors = self.descriptors.blocking_write();
-        let entry = descriptors.raw_entry_mut().from_key(&key);
-        Ok(match entry {
-            RawEntryMut::Occupied(entry) => entry.into_key_value().1.clone(),
-            RawEntryMut::Vacant(entry) => entry
-                .insert(
-                    key,
-                    Arc::new(Box::from(Callback::new(py, callback)?.accept::<ParameterVisitor>(py)?)),
-                )
-                .1
-                .clone(),
-        })
+        let built = Self::new_descriptors(py, callback)?;
+        let mut cache = self.descriptors.write().unwrap_or_else(PoisonError::into_inner);
+        let (_, variants) = cache
+            .functions
+            .entry(code_key)
+            .or_insert_with(|| (code.to_object(py), Vec::new()));
+        let mut evicted = None;
+        let published = match variants.iter().find(|variant| variant.fingerprint == fingerprint) {
+            Some(variant) => variant.descriptors.clone(),
+            None => {
+                if variants.len() >= FUNCTION_VARIANTS_LIMIT {
+                    evicted = Some(variants.remove(0));
+                }
+
+                variants.push(FunctionVariant {
+                    descriptors: built.clone(),
+                    fingerprint,
+                    function,
+                });
+                built
+            }
+        };
+        // Anything holding Python objects which didn't make it into the cache is
+        // dropped after the lock's released.
+        drop(cache);
+        drop(evicted);
+        Ok(published)
+    }
+
+    // Whether calling a callback with DI requires an event loop.
+    //
+    // This is cached against the registered callbacks and only re-checked
+    // through the callback overrides when any are in scope.
+    pub fn requires_async(&self, py: Python, ctx: &Context, descriptors: &Arc<Descriptors>) -> PyResult<bool> {
+        if descriptors.is_async {
+            return Ok(true);
+        }
+
+        // Overrides can swap a dependency for one with a different answer
+        // either way, so the cached answer is only used when there are none.
+        if ctx.has_callback_overrides(py, self) {
+            return self.visit_requires_async(py, Some(ctx), descriptors, &mut HashSet::new());
+        }
+
+        if let Some(requires_async) = descriptors.requires_async.get() {
+            return Ok(*requires_async);
+        }
+
+        let requires_async = self.visit_requires_async(py, None, descriptors, &mut HashSet::new())?;
+        Ok(*descriptors.requires_async.get_or_init(|| requires_async))
     }
 
-    pub fn get_type_dependency_rust<'a>(&'a self, type_: &isize) -> Option<&'a PyObject> {
-        self.type_dependencies.get(type_)
+    // Whether this client or any of its parents may have callback overrides.
+    fn has_callback_overrides(&self, py: Python) -> bool {
+        if self.callback_overrides.snapshot().values().any(Option::is_some) {
+            return true;
+        }
+
+        match self.parent.as_ref() {
+            Some(parent) => match parent.as_ref(py).cast_as::<PyCell<Client>>() {
+                Ok(parent) => parent
+                    .try_borrow()
+                    .map_or(true, |parent| parent.has_callback_overrides(py)),
+                // There's no way to know what another client implementation may override.
+                Err(_) => true,
+            },
+            None => false,
+        }
+    }
+
+    fn visit_requires_async(
+        &self,
+        py: Python,
+        ctx: Option<&Context>,
+        descriptors: &Arc<Descriptors>,
+        visited: &mut HashSet<usize>,
+    ) -> PyResult<bool> {
+        if descriptors.is_async {
+            return Ok(true);
+        }
+
+        // Dependency cycles are left to fail when the callback's called.
+        if !visited.insert(Arc::as_ptr(descriptors) as usize) {
+            return Ok(false);
+        }
+
+        for (_, injected) in descriptors.iter() {
+            let callback = match injected {
+                Injected::Callback(callback) => callback.callback.as_ref(py),
+                Injected::Type(_) => continue,
+            };
+
+            let callback = match ctx {
+                Some(ctx) => ctx
+                    .get_callback_override(py, self, callback)?
+                    .map(|value| value.into_ref(py)),
+                None => None,
+            }
+            .unwrap_or(callback);
+
+            let dependency = self.build_descriptors(py, callback)?;
+            let requires_async = match (ctx, dependency.requires_async.get()) {
+                (None, Some(requires_async)) => *requires_async,
+                _ => self.visit_requires_async(py, ctx, &dependency, visited)?,
+            };
+
+            if requires_async {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    // Returns [None] if a plan can't be used with this client or context.
+    fn binding_plan(
+        &self,
+        py: Python,
+        ctx: &Context,
+        descriptors: &Arc<Descriptors>,
+    ) -> PyResult<Option<Arc<BindingPlan>>> {
+        if !self.plannable || ctx.has_special_cased_types(py) {
+            return Ok(None);
+        }
+
+        // This is loaded before resolving so a concurrent registry change
+        // leaves the new plan already outdated rather than wrongly current.
+        let generation = GENERATION.load(Ordering::Acquire);
+        let key = Arc::as_ptr(descriptors) as usize;
+        if let Some(plan) = self.plans.lock().unwrap_or_else(PoisonError::into_inner).get(&key) {
+            if plan.generation == generation && Weak::ptr_eq(&plan.descriptors, &Arc::downgrade(descriptors)) {
+                return Ok(Some(plan.clone()));
+            }
+        }
+
+        let values = descriptors
+            .iter()
+            .map(|(_, value)| match value {
+                Injected::Type(type_) => type_.resolve_from_client(py, self),
+                Injected::Callback(_) => Ok(None),
+            })
+            .collect::<PyResult<_>>()?;
+        let plan = Arc::new(BindingPlan {
+            descriptors: Arc::downgrade(descriptors),
+            generation,
+            values,
+        });
+        let mut plans = self.plans.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut removed = plans.insert(key, plan.clone()).into_iter().collect::<Vec<_>>();
+        if removed.is_empty() {
+            let dead = plans
+                .iter()
+                .filter(|(_, plan)| plan.descriptors.strong_count() == 0)
+                .map(|(key, _)| *key)
+                .collect::<Vec<_>>();
+            removed.extend(dead.iter().filter_map(|key| plans.remove(key)));
+        }
+
+        // The replaced and pruned plans are dropped after the lock's released.
+        drop(plans);
+        drop(removed);
+        Ok(Some(plan))
+    }
+
+    // Nested calls reuse the current context if it belongs to this client.
+    fn current_or_new_context(slf: &Py<Self>, py: Python) -> PyResult<Context> {
+        if let Ok(ctx) = current_context_var(py)?
+            .call_method0("get")?
+            .cast_as::<PyCell<BasicContext>>()
+        {
+            if ctx.borrow().client.is(slf) {
+                return Ok(Context::Basic(ctx.into()));
+            }
+        }
+
+        Self::new_context(slf, py, vec![])
+    }
+
+    fn new_context(slf: &Py<Self>, py: Python, special_cased_types: Vec<(isize, PyObject)>) -> PyResult<Context> {
+        let mut ctx = BasicContext::new(slf.clone_ref(py));
+        ctx.special_cased_types.extend(special_cased_types);
+        Ok(Context::Basic(Py::new(py, ctx)?))
+    }
+
+    pub fn get_type_dependency_rust(&self, py: Python, type_id: &isize, type_: &PyAny) -> PyResult<Option<PyObject>> {
+        if let Some(value) = self.type_dependencies.get(py, type_id) {
+            return Ok(value);
+        }
+
+        match self.parent.as_ref() {
+            Some(parent) => match parent.as_ref(py).cast_as::<PyCell<Client>>() {
+                Ok(parent) => parent.try_borrow()?.get_type_dependency_rust(py, type_id, type_),
+                Err(_) => call_with_undefined(py, parent, "get_type_dependency", type_),
+            },
+            None => Ok(None),
+        }
     }
 
     pub fn call_with_ctx_rust<'p>(
         self: &PyRef<'p, Self>,
         py: Python<'p>,
-        ctx: &PyRef<'p, BasicContext>,
+        ctx: &Context,
+        callback: &'p PyAny,
+        args: &PyTuple,
+        kwargs: Option<&'p PyDict>,
+    ) -> PyResult<&'p PyAny> {
+        let result = self.call_with_ctx_unchecked(py, ctx, callback, args, kwargs)?;
+        if is_coroutine(py, result)? {
+            Err(AsyncOnlyError::new_err(()))
+        } else {
+            Ok(result)
+        }
+    }
+
+    // Unlike [Client::call_with_ctx_rust] this lets the callback return a
+    // coroutine, leaving it to the caller to await it.
+    pub fn call_with_ctx_unchecked<'p>(
+        self: &PyRef<'p, Self>,
+        py: Python<'p>,
+        ctx: &Context,
         callback: &'p PyAny,
         args: &PyTuple,
-        mut kwargs: Option<&'p PyDict>,
+        kwargs: Option<&'p PyDict>,
     ) -> PyResult<&'p PyAny> {
         let descriptors = self.build_descriptors(py, callback)?;
+        // This avoids resolving dependencies and creating a coroutine which'd
+        // never be awaited.
+        if self.requires_async(py, ctx, &descriptors)? {
+            return Err(AsyncOnlyError::new_err(()));
+        }
+
+        if descriptors.is_empty() {
+            callback.call(args, kwargs)
+        } else {
+            let plan = self.binding_plan(py, ctx, &descriptors)?;
+            let values = descriptors
+                .iter()
+                .enumerate()
+                .map(|(index, (_, value))| {
+                    if let Some(value) = plan.as_ref().and_then(|plan| plan.values[index].as_ref()) {
+                        return Ok(value.as_ref(py));
+                    }
+
+                    match value {
+                        Injected::Type(type_) => type_.resolve(py, self, ctx),
+                        Injected::Callback(callback) => callback.resolve(py, self, ctx),
+                    }
+                })
+                .collect::<PyResult<Vec<&PyAny>>>()?;
+
+            match kwargs {
+                Some(kwargs) => {
+                    for ((name, _), value) in descriptors.iter().zip(values) {
+                        kwargs.set_item(name, value)?;
+                    }
 
-        if !descriptors.is_empty() {
-            let descriptors = descriptors.iter().map(|(key, value)| match value {
-                Injected::Type(type_) => type_.resolve(py, self, ctx).map(|value| (key, value)),
-                Injected::Callback(callback) => callback.resolve(py, self, ctx).map(|value| (key, value)),
-            });
-            if let Some(dict) = kwargs {
-                for entry in descriptors {
-                    let (key, value) = entry?;
-                    dict.set_item(key, value)?;
+                    callback.call(args, Some(kwargs))
                 }
-            } else {
-                kwargs = descriptors
-                    .collect::<PyResult<Vec<(&String, &PyAny)>>>()
-                    .map(|value| Some(value.into_py_dict(py)))?
+                None => call_with_names(py, callback, args, descriptors.names.as_ref(py), &values),
             }
         }
+    }
 
-        let result = callback.call(args, kwargs)?;
-        if import_asyncio(py)?.call_method1("iscoroutine", (result,))?.is_true()? {
-            Err(AsyncOnlyError::new_err(()))
-        } else {
-            Ok(result)
+    // Entry point for sync calls which sets the current context while the
+    // callback and its dependencies are running.
+    //
+    // Calls which require an event loop are run through the client's sync
+    // async bridge if it has one.
+    fn call_with_ctx_sync(
+        slf: Py<Self>,
+        py: Python,
+        ctx: &Context,
+        callback: &PyAny,
+        args: &PyTuple,
+        kwargs: Option<&PyDict>,
+    ) -> PyResult<PyObject> {
+        let slf_borrow = slf.borrow(py);
+        if let Some(bridge) = slf_borrow.sync_async_bridge.as_ref() {
+            let descriptors = slf_borrow.build_descriptors(py, callback)?;
+            if slf_borrow.requires_async(py, ctx, &descriptors)? {
+                let bridge = bridge.clone_ref(py);
+                drop(slf_borrow);
+                let coro = Self::call_with_ctx_coro(
+                    slf,
+                    py,
+                    ctx.clone_ref(py),
+                    callback.to_object(py),
+                    args.into(),
+                    kwargs.map(Into::into),
+                )?;
+                return bridge.run(py, coro).map(|value| value.to_object(py));
+            }
         }
+
+        let current_context = current_context_var(py)?;
+        let token = current_context.call_method1("set", (ctx.to_object(py),))?;
+        let result = slf_borrow
+            .call_with_ctx_rust(py, ctx, callback, args, kwargs)
+            .map(|value| value.to_object(py));
+        current_context.call_method1("reset", (token,))?;
+        result
+    }
+
+    // Entry point for async calls which captures the caller's contextvars so
+    // the callback and its dependencies can be run within them.
+    fn call_with_ctx_coro<'p>(
+        slf: Py<Self>,
+        py: Python<'p>,
+        ctx: Context,
+        callback: PyObject,
+        args: Py<PyTuple>,
+        kwargs: Option<Py<PyDict>>,
+    ) -> PyResult<&'p PyAny> {
+        let slf_borrow = slf.borrow(py);
+        let cancellable = slf_borrow.cancellable.clone_ref(py);
+        let descriptors = slf_borrow.build_descriptors(py, callback.as_ref(py))?;
+        drop(slf_borrow);
+
+        let contextvars_ctx = import_contextvars(py)?.call_method0("copy_context")?;
+        // Every step of the call runs within this copy so the current context
+        // only has to be set once.
+        contextvars_ctx.call_method1("run", (current_context_var(py)?.getattr("set")?, ctx.to_object(py)))?;
+        let state = Arc::new(CallState {
+            cancel_token: Py::new(py, CancelToken::default())?,
+            contextvars_ctx: contextvars_ctx.to_object(py),
+        });
+        let token = state.cancel_token.clone_ref(py);
+        let driver = CallDriver::new(slf, ctx, state, callback, args, kwargs, Some(descriptors));
+        cancellable.as_ref(py).call1((token, Py::new(py, driver)?))
+    }
+
+    // This uses a child cancel token so only this dependency is cancelled when
+    // the timeout expires.
+    pub fn call_with_timeout_async(
+        slf: Py<Self>,
+        py: Python,
+        ctx: Context,
+        state: &CallState,
+        timeout: f64,
+        name: &str,
+        callback: PyObject,
+    ) -> PyResult<impl Future<Output = PyResult<PyObject>> + Send + 'static> {
+        let with_timeout = slf.borrow(py).with_timeout.clone_ref(py);
+        let message = format!(
+            "Timed out after {timeout} seconds while resolving {} for parameter '{name}'",
+            callback.as_ref(py).repr()?
+        );
+        let child_state = Arc::new(CallState {
+            cancel_token: Py::new(py, CancelToken::default())?,
+            contextvars_ctx: state.contextvars_ctx.clone_ref(py),
+        });
+        let child_token = child_state.cancel_token.to_object(py);
+        let args = PyTuple::empty(py).into_py(py);
+        let coro = fut_into_coro(
+            py,
+            Self::call_with_ctx_async_rust(slf, ctx, child_state, callback, args, None),
+        )?;
+
+        await_py1(with_timeout.as_ref(py), &[
+            state.cancel_token.to_object(py).into_ref(py),
+            timeout.into_py(py).into_ref(py),
+            child_token.into_ref(py),
+            coro,
+            message.into_py(py).into_ref(py),
+        ])
     }
 
     pub async fn call_with_ctx_async_rust(
         slf: Py<Self>,
-        ctx: Py<BasicContext>,
+        ctx: Context,
+        state: Arc<CallState>,
         callback: PyObject,
         args: Py<PyTuple>,
         mut kwargs: Option<Py<PyDict>>,
     ) -> PyResult<PyObject> {
-        let (callback_key, callback_clone, all_descriptors, maybe_await) = Python::with_gil(|py| {
+        let (descriptors, scoped) = Python::with_gil(|py| {
             let slf_borrow = slf.borrow(py);
             Ok::<_, PyErr>((
-                callback.as_ref(py).hash()?,
-                callback.clone_ref(py),
-                slf_borrow.descriptors.clone(),
-                slf_borrow.maybe_await.clone_ref(py),
+                slf_borrow.build_descriptors(py, callback.as_ref(py))?,
+                slf_borrow.scoped.clone_ref(py),
             ))
         })?;
 
-        let descriptors = build_descriptors_async(all_descriptors, callback_key, callback_clone).await?;
-
-        let result = Python::with_gil(|py| {
+        let (mut more_kwargs, futures) = Python::with_gil(|py| {
             let slf_borrow = slf.borrow(py);
+            let mut resolved = Vec::<(String, PyObject)>::new();
+            let mut futures = Vec::new();
 
             if descriptors.is_empty() {
-                return Ok::<_, PyErr>(None);
+                return Ok::<_, PyErr>((resolved, futures));
             }
 
-            let ctx_borrow = ctx.borrow(py);
             let kwargs = kwargs.get_or_insert_with(|| PyDict::new(py).into_py(py)).as_ref(py);
-
-            let descriptors = descriptors
-                .iter()
-                .map(|(key, value)| match value {
+            for (index, (key, value)) in descriptors.iter().enumerate() {
+                match value {
                     Injected::Type(type_) => {
-                        let value = type_.resolve(py, &slf_borrow, &ctx_borrow)?;
+                        let value = type_.resolve(py, &slf_borrow, &ctx)?;
                         kwargs.set_item(key, value)?;
-                        Ok(None)
                     }
-                    Injected::Callback(callback) => Ok(Some((
-                        key.to_owned(),
-                        callback.resolve_async(py, slf.clone_ref(py), ctx.clone_ref(py))?,
-                    ))),
-                })
-                .filter_map(Result::transpose)
-                .collect::<PyResult<Vec<_>>>()?;
-
-            if descriptors.is_empty() {
-                return Ok(None);
+                    Injected::Callback(callback) => {
+                        let inline = state.resolve_inline(py, &slf, &ctx, &descriptors, index)?;
+                        if let Some(Inline::Value(value)) = inline {
+                            resolved.push((key.to_owned(), value));
+                        } else if let Some(Inline::Awaitable {
+                            key: cache_key,
+                            awaitable,
+                        }) = inline
+                        {
+                            let driver = CallDriver::for_awaitable(
+                                slf.clone_ref(py),
+                                ctx.clone_ref(py),
+                                state.clone(),
+                                awaitable.as_ref(py),
+                            )?;
+                            let fut = await_py1(slf_borrow.scoped.as_ref(py), &[
+                                state.cancel_token.to_object(py).into_ref(py),
+                                Py::new(py, driver)?.into_ref(py),
+                            ])?;
+                            let ctx = ctx.clone_ref(py);
+                            let token = state.cancel_token.clone_ref(py);
+                            let fut: Pin<Box<dyn Future<Output = PyResult<PyObject>> + Send>> = Box::pin(async move {
+                                let value = fut.await?;
+                                Python::with_gil(|py| {
+                                    Resolved::Fresh(value.as_ref(py)).settle(
+                                        py,
+                                        &ctx,
+                                        cache_key.as_ref(py),
+                                        Some(&token),
+                                    )?;
+                                    Ok(value)
+                                })
+                            });
+                            futures.push((key.to_owned(), fut));
+                        } else {
+                            let fut =
+                                callback.resolve_async(py, slf.clone_ref(py), ctx.clone_ref(py), state.clone(), key)?;
+                            futures.push((key.to_owned(), fut));
+                        }
+                    }
+                }
             }
 
-            Ok(Some(descriptors))
+            Ok((resolved, futures))
         })?;
 
-        if result.is_none() {
-            return Python::with_gil(|py| match kwargs {
-                Some(kwargs) => await_py1(maybe_await.as_ref(py), &[
-                    callback.as_ref(py),
-                    args.as_ref(py),
-                    kwargs.as_ref(py),
-                ]),
-                None => await_py1(maybe_await.as_ref(py), &[
-                    callback.as_ref(py),
-                    args.as_ref(py),
-                    py.None().as_ref(py),
-                ]),
-            })?
-            .await;
-        };
-
-        let iter = result.unwrap();
-        let mut more_kwargs = Vec::<(String, PyObject)>::with_capacity(iter.len());
-        for result in iter {
-            let (name, fut) = result;
-            more_kwargs.push((name, fut.await?));
+        more_kwargs.reserve(futures.len());
+        for (name, fut) in futures {
+            let value = until_cancelled(&state.cancel_token, fut).await?;
+            more_kwargs.push((name, value));
         }
 
-        Python::with_gil(|py| {
-            // At this point kwargs is guaranteed to exist and this makes
-            // handling the lifetimes of kwargs.as_ref(py) easier.
-            let kwargs = kwargs.as_ref().unwrap();
-            let kwargs_ref = kwargs.as_ref(py);
-            for (name, value) in more_kwargs {
-                kwargs_ref.set_item(name, value)?;
+        let fut = Python::with_gil(|py| {
+            if !more_kwargs.is_empty() {
+                // At this point kwargs is guaranteed to exist.
+                let kwargs_ref = kwargs.as_ref().unwrap().as_ref(py);
+                // The callback now owns these resources.
+                CancelToken::release(&state.cancel_token, py, more_kwargs.iter().map(|(_, value)| value))?;
+                for (name, value) in more_kwargs {
+                    kwargs_ref.set_item(name, value)?;
+                }
             }
 
-            await_py1(maybe_await.as_ref(py), &[
-                callback.as_ref(py),
-                args.as_ref(py),
-                kwargs_ref,
+            // The dependencies are already resolved so the driver only has to
+            // call the callback and drive its coroutine.
+            let token = state.cancel_token.to_object(py);
+            let driver = CallDriver::new(slf, ctx, state.clone(), callback, args, kwargs, None);
+            await_py1(scoped.as_ref(py), &[
+                token.into_ref(py),
+                Py::new(py, driver)?.into_ref(py),
             ])
-        })?
-        .await
+        })?;
+        until_cancelled(&state.cancel_token, fut).await
+    }
+}
+
+/// A callback dependency which was resolved inline.
+pub enum Inline {
+    Value(PyObject),
+    // A coroutine returned by a function which isn't a coroutine function.
+    // This still has to be awaited then cached under `key`.
+    Awaitable { key: PyObject, awaitable: PyObject },
+}
+
+/// State shared between every step of an async DI call.
+pub struct CallState {
+    pub cancel_token: Py<CancelToken>,
+    pub contextvars_ctx: PyObject,
+}
+
+impl CallState {
+    // Resolves a sync callback dependency within the call's contextvars rather
+    // than through tokio, returning [None] if it has to be resolved asynchronously.
+    pub fn resolve_inline(
+        &self,
+        py: Python,
+        client: &Py<Client>,
+        ctx: &Context,
+        descriptors: &Arc<Descriptors>,
+        index: usize,
+    ) -> PyResult<Option<Inline>> {
+        let key = match &descriptors[index].1 {
+            Injected::Callback(callback) if callback.resolves_inline(py, &client.borrow(py), ctx)? => {
+                callback.callback.clone_ref(py)
+            }
+            _ => return Ok(None),
+        };
+
+        let descriptors = descriptors.clone();
+        let client = client.clone_ref(py);
+        let ctx = ctx.clone_ref(py);
+        let token = self.cancel_token.clone_ref(py);
+        let resolve = PyCFunction::new_closure(
+            move |args, _| {
+                let py = args.py();
+                let callback = match &descriptors[index].1 {
+                    Injected::Callback(callback) => callback,
+                    Injected::Type(_) => unreachable!(),
+                };
+                callback
+                    .resolve_inline(py, &client.borrow(py), &ctx, &token)
+                    .map(|(value, is_awaitable)| (value, is_awaitable).to_object(py))
+            },
+            py,
+        )?;
+
+        let (value, is_awaitable) = self
+            .contextvars_ctx
+            .call_method1(py, "run", (resolve,))?
+            .extract::<(PyObject, bool)>(py)?;
+        Ok(Some(if is_awaitable {
+            Inline::Awaitable { key, awaitable: value }
+        } else {
+            Inline::Value(value)
+        }))
     }
 }
 
 #[pyo3::pymethods]
 impl Client {
     #[new]
-    #[args("*", introspect_annotations = "true")]
-    fn new(py: Python, introspect_annotations: bool) -> PyResult<Self> {
-        let globals_ = [("iscoroutine", py.import("asyncio")?.getattr("iscoroutine")?)].into_py_dict(py);
-        py.run(
-            r#"
-async def maybe_await(callback, args, kwargs):
-    if kwargs is None:
-        result = callback(*args)
-    else:
-        result = callback(*args, **kwargs)
-
-    if iscoroutine(result):
-        return await result
-
-    return result
-    "#,
-            Some(globals_),
-            None,
-        )
-        .unwrap();
+    #[args(
+        "*",
+        introspect_annotations = "true",
+        parent = "None",
+        resolution_timeout = "None",
+        sync_async_bridge = "None"
+    )]
+    fn new(
+        py: Python,
+        introspect_annotations: bool,
+        parent: Option<PyObject>,
+        resolution_timeout: Option<f64>,
+        sync_async_bridge: Option<&str>,
+    ) -> PyResult<Self> {
+        if resolution_timeout.map_or(false, |timeout| !(timeout.is_finite() && timeout > 0.0)) {
+            return Err(PyValueError::new_err(
+                "resolution_timeout must be a positive finite number",
+            ));
+        }
+
+        let sync_async_bridge = sync_async_bridge
+            .map(|name| SyncAsyncBridge::new(py, name, import_shim(py, "LoopThread")?))
+            .transpose()?;
+
+        let plannable = match parent.as_ref() {
+            Some(parent) => parent.as_ref(py).cast_as::<PyCell<Client>>().map_or(false, |parent| {
+                parent.try_borrow().map_or(false, |parent| parent.plannable)
+            }),
+            None => true,
+        };
 
         Ok(Self {
-            callback_overrides: HashMap::new(),
-            descriptors: Arc::new(RwLock::new(HashMap::new())),
+            callback_overrides: SharedRegistry::default(),
+            cancellable: import_shim(py, "cancellable")?.to_object(py),
+            descriptors: DescriptorMap::default(),
             introspect_annotations,
-            maybe_await: globals_.get_item("maybe_await").unwrap().to_object(py),
-            type_dependencies: HashMap::new(),
+            parent,
+            plans: Mutex::default(),
+            plannable,
+            resolution_timeout,
+            scoped: import_shim(py, "scoped")?.to_object(py),
+            sync_async_bridge,
+            type_dependencies: SharedRegistry::default(),
+            with_timeout: import_shim(py, "with_timeout")?.to_object(py),
+        })
+    }
+
+    fn create_child(slf: PyRef<Self>, py: Python) -> PyResult<Py<Self>> {
+        // Descriptors don't depend on the registered dependencies so they can be
+        // shared with the parent.
+        Py::new(py, Self {
+            callback_overrides: SharedRegistry::default(),
+            cancellable: slf.cancellable.clone_ref(py),
+            descriptors: slf.descriptors.clone(),
+            introspect_annotations: slf.introspect_annotations,
+            plans: Mutex::default(),
+            plannable: slf.plannable,
+            resolution_timeout: slf.resolution_timeout,
+            scoped: slf.scoped.clone_ref(py),
+            sync_async_bridge: slf.sync_async_bridge.as_ref().map(|bridge| bridge.clone_ref(py)),
+            with_timeout: slf.with_timeout.clone_ref(py),
+            parent: Some(slf.into_py(py)),
+            type_dependencies: SharedRegistry::default(),
         })
     }
 
@@ -310,27 +1199,20 @@ async def maybe_await(callback, args, kwargs):
         args: &PyTuple,
         kwargs: Option<&PyDict>,
     ) -> PyResult<PyObject> {
-        Self::call_with_ctx(
-            slf.clone_ref(py),
-            py,
-            Py::new(py, BasicContext::new(slf))?,
-            callback,
-            args,
-            kwargs,
-        )
+        let ctx = Self::current_or_new_context(&slf, py)?;
+        Self::call_with_ctx_sync(slf, py, &ctx, callback, args, kwargs)
     }
 
     #[args(ctx, callback, "/", args = "*", kwargs = "**")]
     pub fn call_with_ctx(
-        _slf: Py<Self>,
+        slf: PyRef<Self>,
         py: Python,
-        ctx: Py<BasicContext>,
+        ctx: &PyAny,
         callback: &PyAny,
         args: &PyTuple,
         kwargs: Option<&PyDict>,
     ) -> PyResult<PyObject> {
-        println!("b");
-        ctx.borrow(py).call_with_di(py, callback, args, kwargs)
+        Self::call_with_ctx_sync(slf.into(), py, &Context::new(py, ctx), callback, args, kwargs)
     }
 
     #[args(callback, "/", args = "*", kwargs = "**")]
@@ -341,45 +1223,75 @@ async def maybe_await(callback, args, kwargs):
         args: Py<PyTuple>,
         kwargs: Option<Py<PyDict>>,
     ) -> PyResult<&PyAny> {
-        Self::call_with_ctx_async(
-            slf.clone_ref(py),
-            py,
-            Py::new(py, BasicContext::new(slf))?,
-            callback,
-            args,
-            kwargs,
-        )
+        let ctx = Self::current_or_new_context(&slf, py)?;
+        Self::call_with_ctx_coro(slf, py, ctx, callback, args, kwargs)
     }
 
     #[args(ctx, callback, "/", args = "*", kwargs = "**")]
     pub fn call_with_ctx_async(
-        _slf: Py<Self>,
+        slf: Py<Self>,
         py: Python<'_>,
-        ctx: Py<BasicContext>,
+        ctx: &PyAny,
         callback: PyObject,
         args: Py<PyTuple>,
         kwargs: Option<Py<PyDict>>,
     ) -> PyResult<&PyAny> {
-        BasicContext::call_with_async_di(ctx, py, callback, args, kwargs)
+        let ctx = Context::new(py, ctx);
+        Self::call_with_ctx_coro(slf, py, ctx, callback, args, kwargs)
+    }
+
+    #[args(overrides, callback, "/", args = "*", kwargs = "**")]
+    fn call_with_overrides(
+        slf: Py<Self>,
+        py: Python,
+        overrides: &PyMapping,
+        callback: &PyAny,
+        args: &PyTuple,
+        kwargs: Option<&PyDict>,
+    ) -> PyResult<PyObject> {
+        let ctx = Self::new_context(&slf, py, hash_mapping(py, Some(overrides))?)?;
+        Self::call_with_ctx_sync(slf, py, &ctx, callback, args, kwargs)
+    }
+
+    #[args(overrides, callback, "/", args = "*", kwargs = "**")]
+    fn call_with_async_overrides<'p>(
+        slf: Py<Self>,
+        py: Python<'p>,
+        overrides: &PyMapping,
+        callback: PyObject,
+        args: Py<PyTuple>,
+        kwargs: Option<Py<PyDict>>,
+    ) -> PyResult<&'p PyAny> {
+        let ctx = Self::new_context(&slf, py, hash_mapping(py, Some(overrides))?)?;
+        Self::call_with_ctx_coro(slf, py, ctx, callback, args, kwargs)
+    }
+
+    #[args(types = "None", "/", "*", callbacks = "None")]
+    fn override_types(
+        slf: Py<Self>,
+        py: Python,
+        types: Option<&PyMapping>,
+        callbacks: Option<&PyMapping>,
+    ) -> PyResult<Overrides> {
+        Ok(Overrides {
+            callbacks: hash_mapping(py, callbacks)?,
+            client: slf,
+            snapshot: None,
+            types: hash_mapping(py, types)?,
+        })
     }
 
     #[args(type_, value, "/")]
-    fn set_type_dependency<'p>(
-        mut self: PyRefMut<'p, Self>,
-        type_: &PyAny,
-        value: PyObject,
-    ) -> PyResult<PyRefMut<'p, Self>> {
-        self.type_dependencies.insert(type_.hash()?, value);
-        Ok(self)
+    fn set_type_dependency<'p>(slf: PyRef<'p, Self>, type_: &PyAny, value: PyObject) -> PyResult<PyRef<'p, Self>> {
+        let key = type_.hash()?;
+        slf.type_dependencies
+            .update(|registry| registry.insert(key, Some(value)));
+        Ok(slf)
     }
 
     #[args(type_, "/", "*", default)]
     pub fn get_type_dependency(&self, py: Python, type_: &PyAny, default: Option<PyObject>) -> PyResult<PyObject> {
-        if let Some(value) = self
-            .type_dependencies
-            .get(&type_.hash()?)
-            .map(|value| value.clone_ref(py))
-        {
+        if let Some(value) = self.get_type_dependency_rust(py, &type_.hash()?, type_)? {
             return Ok(value);
         };
 
@@ -387,82 +1299,373 @@ async def maybe_await(callback, args, kwargs):
     }
 
     #[args(type_, "/")]
-    fn remove_type_dependency<'p>(mut self: PyRefMut<'p, Self>, type_: &PyAny) -> PyResult<PyRefMut<'p, Self>> {
-        if self.type_dependencies.remove(&type_.hash()?).is_none() {
-            Err(PyKeyError::new_err(format!("Type dependency not found: {type_}")))
-        } else {
-            Ok(self)
+    fn remove_type_dependency<'p>(slf: PyRef<'p, Self>, py: Python<'p>, type_: &PyAny) -> PyResult<PyRef<'p, Self>> {
+        let key = type_.hash()?;
+        if slf.get_type_dependency_rust(py, &key, type_)?.is_none() {
+            return Err(PyKeyError::new_err(format!("Type dependency not found: {type_}")));
         }
+
+        let has_parent = slf.parent.is_some();
+        slf.type_dependencies.update(|registry| {
+            if has_parent {
+                registry.insert(key, None)
+            } else {
+                registry.remove(&key)
+            }
+        });
+        Ok(slf)
     }
 
     #[args(callback, override_, "/")]
     fn set_callback_override<'p>(
-        mut self: PyRefMut<'p, Self>,
+        slf: PyRef<'p, Self>,
         callback: &PyAny,
         override_: PyObject,
-    ) -> PyResult<PyRefMut<'p, Self>> {
-        self.callback_overrides.insert(callback.hash()?, override_);
-        Ok(self)
+    ) -> PyResult<PyRef<'p, Self>> {
+        let key = callback.hash()?;
+        slf.callback_overrides
+            .update(|registry| registry.insert(key, Some(override_)));
+        Ok(slf)
     }
 
     #[args(callback, "/")]
-    pub fn get_callback_override<'p>(&'p self, py: Python<'p>, callback: &'p PyAny) -> PyResult<Option<&'p PyAny>> {
-        Ok(self
-            .callback_overrides
-            .get(&callback.hash()?)
-            .map(|value| value.as_ref(py)))
+    pub fn get_callback_override(&self, py: Python, callback: &PyAny) -> PyResult<Option<PyObject>> {
+        if let Some(value) = self.callback_overrides.get(py, &callback.hash()?) {
+            return Ok(value);
+        }
+
+        match self.parent.as_ref() {
+            Some(parent) => match parent.as_ref(py).cast_as::<PyCell<Client>>() {
+                Ok(parent) => parent.try_borrow()?.get_callback_override(py, callback),
+                Err(_) => {
+                    let result = parent.call_method1(py, "get_callback_override", (callback,))?;
+                    Ok(if result.is_none(py) { None } else { Some(result) })
+                }
+            },
+            None => Ok(None),
+        }
     }
 
     #[args(callback, "/")]
-    fn remove_callback_override<'p>(mut self: PyRefMut<'p, Self>, callback: &PyAny) -> PyResult<PyRefMut<'p, Self>> {
-        if self.callback_overrides.remove(&callback.hash()?).is_none() {
-            Err(PyKeyError::new_err(format!(
+    fn remove_callback_override<'p>(
+        slf: PyRef<'p, Self>,
+        py: Python<'p>,
+        callback: &PyAny,
+    ) -> PyResult<PyRef<'p, Self>> {
+        if slf.get_callback_override(py, callback)?.is_none() {
+            return Err(PyKeyError::new_err(format!(
                 "Callback override not found: {}",
                 callback
-            )))
-        } else {
-            Ok(self)
+            )));
+        }
+
+        let key = callback.hash()?;
+        let has_parent = slf.parent.is_some();
+        slf.callback_overrides.update(|registry| {
+            if has_parent {
+                registry.insert(key, None)
+            } else {
+                registry.remove(&key)
+            }
+        });
+        Ok(slf)
+    }
+}
+
+fn hash_mapping(py: Python, mapping: Option<&PyMapping>) -> PyResult<Vec<(isize, PyObject)>> {
+    let mapping = match mapping {
+        Some(mapping) => mapping,
+        None => return Ok(vec![]),
+    };
+
+    mapping
+        .items()?
+        .iter()?
+        .map(|entry| {
+            let (key, value) = entry?.extract::<(&PyAny, &PyAny)>()?;
+            Ok((key.hash()?, value.to_object(py)))
+        })
+        .collect()
+}
+
+type RegistrySnapshot = Vec<(isize, Option<Option<PyObject>>)>;
+
+// Entries set to [None] are removed from the registry. This returns the
+// entries needed to undo the change.
+fn swap_entries(registry: &mut Registry, entries: RegistrySnapshot) -> RegistrySnapshot {
+    entries
+        .into_iter()
+        .rev()
+        .map(|(key, entry)| {
+            let old = match entry {
+                Some(entry) => registry.insert(key, entry),
+                None => registry.remove(&key),
+            };
+            (key, old)
+        })
+        .collect()
+}
+
+/// Context manager returned by `Client.override_types`.
+///
+/// This temporarily replaces entries in the client's type dependencies and
+/// callback overrides then restores them exactly on exit.
+#[pyo3::pyclass]
+pub struct Overrides {
+    callbacks: Vec<(isize, PyObject)>,
+    client: Py<Client>,
+    snapshot: Option<(RegistrySnapshot, RegistrySnapshot)>,
+    types: Vec<(isize, PyObject)>,
+}
+
+impl Overrides {
+    fn enter(&mut self, py: Python) -> PyResult<()> {
+        if self.snapshot.is_some() {
+            return Err(PyRuntimeError::new_err("These overrides are already active"));
+        }
+
+        let to_entries = |entries: &[(isize, PyObject)]| -> RegistrySnapshot {
+            entries
+                .iter()
+                .map(|(key, value)| (*key, Some(Some(value.clone_ref(py)))))
+                .collect()
+        };
+        let client = self.client.borrow(py);
+        let types = client
+            .type_dependencies
+            .update(|registry| swap_entries(registry, to_entries(&self.types)));
+        let callbacks = client
+            .callback_overrides
+            .update(|registry| swap_entries(registry, to_entries(&self.callbacks)));
+        drop(client);
+        self.snapshot = Some((types, callbacks));
+        Ok(())
+    }
+
+    fn exit(&mut self, py: Python) -> PyResult<()> {
+        let (types, callbacks) = self
+            .snapshot
+            .take()
+            .ok_or_else(|| PyRuntimeError::new_err("These overrides aren't active"))?;
+
+        let client = self.client.borrow(py);
+        client
+            .type_dependencies
+            .update(|registry| swap_entries(registry, types));
+        client
+            .callback_overrides
+            .update(|registry| swap_entries(registry, callbacks));
+        Ok(())
+    }
+}
+
+#[pyo3::pymethods]
+impl Overrides {
+    fn __enter__(&mut self, py: Python) -> PyResult<()> {
+        self.enter(py)
+    }
+
+    fn __exit__(&mut self, py: Python, _exc_type: &PyAny, _exc_value: &PyAny, _traceback: &PyAny) -> PyResult<()> {
+        self.exit(py)
+    }
+
+    fn __aenter__(&mut self, py: Python) -> PyResult<Ready> {
+        self.enter(py)?;
+        Ok(Ready::new(py.None()))
+    }
+
+    fn __aexit__(&mut self, py: Python, _exc_type: &PyAny, _exc_value: &PyAny, _traceback: &PyAny) -> PyResult<Ready> {
+        self.exit(py)?;
+        Ok(Ready::new(py.None()))
+    }
+}
+
+/// The context a callback's dependencies are being resolved within.
+///
+/// [BasicContext] is handled natively while any other `alluka.abc.Context`
+/// implementation is used through its public Python methods.
+pub enum Context {
+    Basic(Py<BasicContext>),
+    Abstract(PyObject),
+}
+
+impl ToPyObject for Context {
+    fn to_object(&self, py: Python) -> PyObject {
+        match self {
+            Self::Basic(ctx) => ctx.to_object(py),
+            Self::Abstract(ctx) => ctx.clone_ref(py),
+        }
+    }
+}
+
+impl Context {
+    pub fn new(py: Python, ctx: &PyAny) -> Self {
+        match ctx.cast_as::<PyCell<BasicContext>>() {
+            Ok(ctx) => Self::Basic(ctx.into()),
+            Err(_) => Self::Abstract(ctx.to_object(py)),
+        }
+    }
+
+    pub fn clone_ref(&self, py: Python) -> Self {
+        match self {
+            Self::Basic(ctx) => Self::Basic(ctx.clone_ref(py)),
+            Self::Abstract(ctx) => Self::Abstract(ctx.clone_ref(py)),
+        }
+    }
+
+    pub fn cache_result(&self, py: Python, callback: &PyAny, value: PyObject) -> PyResult<()> {
+        match self {
+            Self::Basic(ctx) => ctx.borrow(py).cache_result(callback, value),
+            Self::Abstract(ctx) => ctx.call_method1(py, "cache_result", (callback, value)).map(|_| ()),
+        }
+    }
+
+    pub fn get_callback_override(&self, py: Python, client: &Client, callback: &PyAny) -> PyResult<Option<PyObject>> {
+        match self {
+            Self::Basic(ctx) => ctx.borrow(py).get_callback_override_rust(py, client, callback),
+            Self::Abstract(_) => client.get_callback_override(py, callback),
+        }
+    }
+
+    pub fn get_cached_result(&self, py: Python, callback: &PyAny) -> PyResult<Option<PyObject>> {
+        match self {
+            Self::Basic(ctx) => ctx.borrow(py).get_cached_result_rust(py, callback),
+            Self::Abstract(ctx) => call_with_undefined(py, ctx, "get_cached_result", callback),
+        }
+    }
+
+    pub fn get_type_dependency(
+        &self,
+        py: Python,
+        client: &Client,
+        type_id: &isize,
+        type_: &PyAny,
+    ) -> PyResult<Option<PyObject>> {
+        match self {
+            Self::Basic(ctx) => ctx.borrow(py).get_type_dependency_rust(py, client, type_id, type_),
+            Self::Abstract(ctx) => call_with_undefined(py, ctx, "get_type_dependency", type_),
+        }
+    }
+
+    pub fn has_callback_overrides(&self, py: Python, client: &Client) -> bool {
+        match self {
+            Self::Basic(ctx) => {
+                ctx.borrow(py)
+                    .find_in_chain(py, &|ctx| (!ctx.callback_overrides.is_empty()).then_some(()))
+                    .map_or(true, |found| found.is_some())
+                    || client.has_callback_overrides(py)
+            }
+            Self::Abstract(_) => client.has_callback_overrides(py),
+        }
+    }
+
+    pub fn has_special_cased_types(&self, py: Python) -> bool {
+        match self {
+            Self::Basic(ctx) => ctx
+                .borrow(py)
+                .find_in_chain(py, &|ctx| (!ctx.special_cased_types.is_empty()).then_some(()))
+                .map_or(true, |found| found.is_some()),
+            // There's no way to know what an abstract context may override.
+            Self::Abstract(_) => true,
+        }
+    }
+
+    // Abstract contexts don't support deduplicating in-flight resolutions.
+    pub fn get_in_flight(&self, py: Python, callback: &PyAny) -> PyResult<Option<InFlight>> {
+        match self {
+            Self::Basic(ctx) => {
+                let key = callback.hash()?;
+                Ok(Some(
+                    ctx.borrow(py).in_flight.borrow_mut().entry(key).or_default().clone(),
+                ))
+            }
+            Self::Abstract(_) => Ok(None),
+        }
+    }
+
+    pub fn remove_in_flight(&self, py: Python, callback: &PyAny, in_flight: &InFlight) -> PyResult<()> {
+        if let Self::Basic(ctx) = self {
+            let key = callback.hash()?;
+            let ctx = ctx.borrow(py);
+            let mut entries = ctx.in_flight.borrow_mut();
+            // Another resolution may've already replaced this entry.
+            if entries.get(&key).map_or(false, |entry| Arc::ptr_eq(entry, in_flight)) {
+                entries.remove(&key);
+            }
         }
+
+        Ok(())
     }
 }
 
+fn call_with_undefined(py: Python, ctx: &PyObject, method: &str, arg: &PyAny) -> PyResult<Option<PyObject>> {
+    let undefined = undefined(py)?;
+    let kwargs = [("default", undefined.clone_ref(py))].into_py_dict(py);
+    let result = ctx.call_method(py, method, (arg,), Some(kwargs))?;
+    Ok(if result.is(&undefined) { None } else { Some(result) })
+}
+
 #[pyo3::pyclass(subclass)]
 pub struct BasicContext {
+    callback_overrides: HashMap<isize, PyObject>,
     pub client: Py<Client>,
-    result_cache: HashMap<isize, PyObject>,
+    in_flight: RefCell<HashMap<isize, InFlight>>,
+    parent: Option<Py<BasicContext>>,
+    result_cache: RefCell<HashMap<isize, PyObject>>,
     special_cased_types: HashMap<isize, PyObject>,
 }
 
 impl BasicContext {
-    pub fn get_type_dependency_rust<'p>(
-        &'p self,
-        client: &'p PyRef<'p, Client>,
-        type_: &isize,
-    ) -> Option<&'p PyObject> {
-        self.special_cased_types
-            .get(type_)
-            .or_else(|| client.get_type_dependency_rust(type_))
+    // Returns the first value found walking from this context up through its
+    // parents. Lookups fall through to the parent context while writes stay local.
+    fn find_in_chain<T>(&self, py: Python, get: &impl Fn(&Self) -> Option<T>) -> PyResult<Option<T>> {
+        if let Some(value) = get(self) {
+            return Ok(Some(value));
+        }
+
+        match self.parent.as_ref() {
+            Some(parent) => parent.try_borrow(py)?.find_in_chain(py, get),
+            None => Ok(None),
+        }
     }
 
-    pub fn call_with_di_rust<'p>(
-        self: &PyRef<'p, Self>,
-        py: Python<'p>,
-        client: &PyRef<'p, Client>,
-        callback: &'p PyAny,
-        args: &PyTuple,
-        kwargs: Option<&'p PyDict>,
-    ) -> PyResult<&'p PyAny> {
-        client.call_with_ctx_rust(py, self, callback, args, kwargs)
+    pub fn get_cached_result_rust(&self, py: Python, callback: &PyAny) -> PyResult<Option<PyObject>> {
+        let key = callback.hash()?;
+        self.find_in_chain(py, &|ctx| {
+            ctx.result_cache.borrow().get(&key).map(|value| value.clone_ref(py))
+        })
     }
 
-    pub fn call_with_async_di_rust(
-        slf: Py<Self>,
-        client: Py<Client>,
-        callback: PyObject,
-        args: Py<PyTuple>,
-        kwargs: Option<Py<PyDict>>,
-    ) -> impl Future<Output = PyResult<PyObject>> {
-        Client::call_with_ctx_async_rust(client, slf, callback, args, kwargs)
+    pub fn get_callback_override_rust(
+        &self,
+        py: Python,
+        client: &Client,
+        callback: &PyAny,
+    ) -> PyResult<Option<PyObject>> {
+        let key = callback.hash()?;
+        if let Some(value) = self.find_in_chain(py, &|ctx| {
+            ctx.callback_overrides.get(&key).map(|value| value.clone_ref(py))
+        })? {
+            return Ok(Some(value));
+        }
+
+        client.get_callback_override(py, callback)
+    }
+
+    pub fn get_type_dependency_rust(
+        &self,
+        py: Python,
+        client: &Client,
+        type_id: &isize,
+        type_: &PyAny,
+    ) -> PyResult<Option<PyObject>> {
+        if let Some(value) = self.find_in_chain(py, &|ctx| {
+            ctx.special_cased_types.get(type_id).map(|value| value.clone_ref(py))
+        })? {
+            return Ok(Some(value));
+        }
+
+        client.get_type_dependency_rust(py, type_id, type_)
     }
 }
 
@@ -472,33 +1675,46 @@ impl BasicContext {
     #[args(client, "/")]
     fn new(client: Py<Client>) -> Self {
         Self {
+            callback_overrides: HashMap::with_capacity(0),
             client,
-            result_cache: HashMap::with_capacity(0),
+            in_flight: RefCell::new(HashMap::with_capacity(0)),
+            parent: None,
+            result_cache: RefCell::new(HashMap::with_capacity(0)),
             special_cased_types: HashMap::with_capacity(0),
         }
     }
 
+    fn child(slf: Py<Self>, py: Python) -> PyResult<Py<Self>> {
+        let mut child = Self::new(slf.borrow(py).client.clone_ref(py));
+        child.parent = Some(slf);
+        Py::new(py, child)
+    }
+
     #[getter]
     fn get_injection_client(&self, py: Python) -> Py<Client> {
         self.client.clone_ref(py)
     }
 
     #[args(callback, value, "/")]
-    fn cache_result(&mut self, callback: &PyAny, value: PyObject) -> PyResult<()> {
-        self.result_cache.insert(callback.hash()?, value);
+    fn cache_result(&self, callback: &PyAny, value: PyObject) -> PyResult<()> {
+        // Neither hashing the callback nor dropping the replaced value (which
+        // may both call into Python) happen while the cache is borrowed.
+        let key = callback.hash()?;
+        let replaced = self.result_cache.borrow_mut().insert(key, value);
+        drop(replaced);
         Ok(())
     }
 
     #[args(callback, "/", args = "*", kwargs = "**")]
-    pub fn call_with_di<'p>(
-        self: PyRef<'p, Self>,
-        py: Python<'p>,
+    pub fn call_with_di(
+        slf: Py<Self>,
+        py: Python,
         callback: &PyAny,
         args: &PyTuple,
         kwargs: Option<&PyDict>,
     ) -> PyResult<PyObject> {
-        self.call_with_di_rust(py, &self.client.borrow(py), callback, args, kwargs)
-            .map(|value| value.to_object(py))
+        let client = slf.borrow(py).client.clone_ref(py);
+        Client::call_with_ctx_sync(client, py, &Context::Basic(slf), callback, args, kwargs)
     }
 
     #[args(callback, "/", args = "*", kwargs = "**")]
@@ -510,19 +1726,12 @@ impl BasicContext {
         kwargs: Option<Py<PyDict>>,
     ) -> PyResult<&PyAny> {
         let client = slf.borrow(py).client.clone_ref(py);
-        fut_into_coro(py, async move {
-            // TODO: retain locals
-            Self::call_with_async_di_rust(slf, client, callback, args, kwargs).await
-        })
+        Client::call_with_ctx_coro(client, py, Context::Basic(slf), callback, args, kwargs)
     }
 
     #[args(callback, "/", "*", default)]
     fn get_cached_result(&self, py: Python, callback: &PyAny, default: Option<PyObject>) -> PyResult<PyObject> {
-        if let Some(result) = self
-            .result_cache
-            .get(&callback.hash()?)
-            .map(|value| value.clone_ref(py))
-        {
+        if let Some(result) = self.get_cached_result_rust(py, callback)? {
             return Ok(result);
         }
 
@@ -531,39 +1740,75 @@ impl BasicContext {
 
     #[args(type_, "/", "*", default)]
     fn get_type_dependency(&self, py: Python, type_: &PyAny, default: Option<PyObject>) -> PyResult<PyObject> {
-        let hash = type_.hash()?;
-        if let Some(result) = self.special_cased_types.get(&hash) {
-            return Ok(result.clone_ref(py));
-        }
-
-        if let Some(result) = self.get_type_dependency_rust(&self.client.borrow(py), &type_.hash()?) {
-            return Ok(result.clone_ref(py));
+        if let Some(result) = self.get_type_dependency_rust(py, &self.client.borrow(py), &type_.hash()?, type_)? {
+            return Ok(result);
         }
 
         default.map(Ok).unwrap_or_else(|| undefined(py))
     }
 
     #[args(type_, value, "/")]
-    fn _set_type_special_case<'p>(
+    fn set_type_dependency<'p>(
         mut self: PyRefMut<'p, Self>,
-        py: Python<'p>,
         type_: &PyAny,
-        value: &PyAny,
+        value: PyObject,
     ) -> PyResult<PyRefMut<'p, Self>> {
-        self.special_cased_types.insert(type_.hash()?, value.to_object(py));
+        self.special_cased_types.insert(type_.hash()?, value);
         Ok(self)
     }
 
-    fn _remove_type_special_case<'p>(mut self: PyRefMut<'p, Self>, type_: &PyAny) -> PyResult<PyRefMut<'p, Self>> {
+    #[args(type_, "/")]
+    fn remove_type_dependency<'p>(mut self: PyRefMut<'p, Self>, type_: &PyAny) -> PyResult<PyRefMut<'p, Self>> {
         if self.special_cased_types.remove(&type_.hash()?).is_none() {
             Err(PyKeyError::new_err(format!("Type dependency not found: {type_}")))
         } else {
             Ok(self)
         }
     }
+
+    #[args(callback, override_, "/")]
+    fn set_callback_override<'p>(
+        mut self: PyRefMut<'p, Self>,
+        callback: &PyAny,
+        override_: PyObject,
+    ) -> PyResult<PyRefMut<'p, Self>> {
+        self.callback_overrides.insert(callback.hash()?, override_);
+        Ok(self)
+    }
+
+    #[args(callback, "/")]
+    fn get_callback_override(&self, py: Python, callback: &PyAny) -> PyResult<Option<PyObject>> {
+        self.get_callback_override_rust(py, &self.client.borrow(py), callback)
+    }
+
+    #[args(callback, "/")]
+    fn remove_callback_override<'p>(mut self: PyRefMut<'p, Self>, callback: &PyAny) -> PyResult<PyRefMut<'p, Self>> {
+        if self.callback_overrides.remove(&callback.hash()?).is_none() {
+            Err(PyKeyError::new_err(format!(
+                "Callback override not found: {}",
+                callback
+            )))
+        } else {
+            Ok(self)
+        }
+    }
+
+    #[args(type_, value, "/")]
+    fn _set_type_special_case<'p>(
+        self: PyRefMut<'p, Self>,
+        type_: &PyAny,
+        value: PyObject,
+    ) -> PyResult<PyRefMut<'p, Self>> {
+        self.set_type_dependency(type_, value)
+    }
+
+    #[args(type_, "/")]
+    fn _remove_type_special_case<'p>(self: PyRefMut<'p, Self>, type_: &PyAny) -> PyResult<PyRefMut<'p, Self>> {
+        self.remove_type_dependency(type_)
+    }
 }
 
-fn undefined(py: Python) -> PyResult<PyObject> {
+pub fn undefined(py: Python) -> PyResult<PyObject> {
     import_alluka(py)?
         .getattr("abc")?
         .getattr("UNDEFINED")
@@ -0,0 +1,349 @@
+// BSD 3-Clause License
+//
+// Copyright (c) 2022, Lucina
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+//   list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+//   this list of conditions and the following disclaimer in the documentation
+//   and/or other materials provided with the distribution.
+//
+// * Neither the name of the copyright holder nor the names of its contributors
+//   may be used to endorse or promote products derived from this software
+//   without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR
+// CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF
+// SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+// INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN
+// CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
+// ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+// POSSIBILITY OF SUCH DAMAGE.
+use std::sync::Arc;
+
+use pyo3::exceptions::{PyBaseException, PyRuntimeError, PyStopIteration};
+use pyo3::pyclass::IterNextOutput;
+use pyo3::types::{PyDict, PyTuple};
+use pyo3::{IntoPy, Py, PyAny, PyErr, PyObject, PyRef, PyResult, Python, ToPyObject};
+use pyo3_anyio::tokio::fut_into_coro;
+
+use crate::cancel::CancelToken;
+use crate::client::{is_coroutine, CallState, Client, Context, Descriptors, Inline};
+use crate::types::{Injected, Resolved};
+
+enum Awaiting {
+    // Callback dependencies are resolved through tokio so they can be
+    // deduplicated, retried and timed out.
+    Dependency(String, PyObject),
+    // The callback's own awaitable, which is stepped within the call's
+    // contextvars.
+    Callback(PyObject),
+    // An awaitable returned by an inline callback dependency which isn't a
+    // coroutine function, stepped within the call's contextvars then cached
+    // under `key`.
+    Inline {
+        name: String,
+        key: PyObject,
+        iterator: PyObject,
+    },
+}
+
+enum Input {
+    Send(PyObject),
+    Throw(PyErr),
+}
+
+/// Awaitable which resolves a callback's dependencies then drives the
+/// coroutine it returns directly through `send` and `throw`.
+///
+/// Type dependencies and sync callback dependencies are resolved inline so
+/// calls with no async dependencies never go through tokio, and cancellation is
+/// thrown straight into whatever is currently being awaited.
+#[pyo3::pyclass]
+pub struct CallDriver {
+    args: Py<PyTuple>,
+    awaiting: Option<Awaiting>,
+    callback: PyObject,
+    client: Py<Client>,
+    ctx: Context,
+    descriptors: Option<Arc<Descriptors>>,
+    done: bool,
+    index: usize,
+    kwargs: Option<Py<PyDict>>,
+    resolved: Vec<PyObject>,
+    state: Arc<CallState>,
+}
+
+impl CallDriver {
+    pub fn new(
+        client: Py<Client>,
+        ctx: Context,
+        state: Arc<CallState>,
+        callback: PyObject,
+        args: Py<PyTuple>,
+        kwargs: Option<Py<PyDict>>,
+        descriptors: Option<Arc<Descriptors>>,
+    ) -> Self {
+        Self {
+            args,
+            awaiting: None,
+            callback,
+            client,
+            ctx,
+            descriptors,
+            done: false,
+            index: 0,
+            kwargs,
+            resolved: Vec::new(),
+            state,
+        }
+    }
+
+    /// Create a driver which only drives an awaitable within the call's
+    /// contextvars.
+    pub fn for_awaitable(client: Py<Client>, ctx: Context, state: Arc<CallState>, awaitable: &PyAny) -> PyResult<Self> {
+        let py = awaitable.py();
+        let mut driver = Self::new(
+            client,
+            ctx,
+            state,
+            awaitable.to_object(py),
+            PyTuple::empty(py).into_py(py),
+            None,
+            None,
+        );
+        driver.awaiting = Some(Awaiting::Callback(awaitable.call_method0("__await__")?.into()));
+        Ok(driver)
+    }
+
+    fn kwargs<'p>(&mut self, py: Python<'p>) -> &'p PyDict {
+        self.kwargs
+            .get_or_insert_with(|| PyDict::new(py).into_py(py))
+            .clone_ref(py)
+            .into_ref(py)
+    }
+
+    fn step(&mut self, py: Python, input: Input) -> PyResult<IterNextOutput<PyObject, PyObject>> {
+        if self.done {
+            return Err(PyRuntimeError::new_err("Cannot reuse an already awaited DI call"));
+        }
+
+        let result = self.step_inner(py, input);
+        if !matches!(result, Ok(IterNextOutput::Yield(_))) {
+            self.done = true;
+        }
+
+        result
+    }
+
+    fn step_inner(&mut self, py: Python, mut input: Input) -> PyResult<IterNextOutput<PyObject, PyObject>> {
+        loop {
+            if let Some(awaiting) = self.awaiting.take() {
+                let (iterator, context) = match &awaiting {
+                    Awaiting::Dependency(_, iterator) => (iterator, None),
+                    Awaiting::Callback(iterator) | Awaiting::Inline { iterator, .. } => {
+                        (iterator, Some(&self.state.contextvars_ctx))
+                    }
+                };
+                let (method, arg) = match input {
+                    Input::Send(value) => (iterator.getattr(py, "send")?, value),
+                    Input::Throw(err) => (iterator.getattr(py, "throw")?, err.into_py(py)),
+                };
+                let result = match context {
+                    Some(context) => context.call_method1(py, "run", (method, arg)),
+                    None => method.call1(py, (arg,)),
+                };
+
+                let value: PyObject = match result {
+                    Ok(value) => {
+                        self.awaiting = Some(awaiting);
+                        return Ok(IterNextOutput::Yield(value));
+                    }
+                    Err(err) if err.is_instance_of::<PyStopIteration>(py) => err.value(py).getattr("value")?.into(),
+                    Err(err) => return Err(err),
+                };
+
+                match awaiting {
+                    Awaiting::Dependency(name, _) => {
+                        self.kwargs(py).set_item(name, value.clone_ref(py))?;
+                        self.resolved.push(value);
+                        input = Input::Send(py.None());
+                    }
+                    Awaiting::Inline { name, key, .. } => {
+                        Resolved::Fresh(value.as_ref(py)).settle(
+                            py,
+                            &self.ctx,
+                            key.as_ref(py),
+                            Some(&self.state.cancel_token),
+                        )?;
+                        self.kwargs(py).set_item(name, value.clone_ref(py))?;
+                        self.resolved.push(value);
+                        input = Input::Send(py.None());
+                    }
+                    Awaiting::Callback(_) => return Ok(IterNextOutput::Return(value)),
+                }
+            } else if let Input::Throw(err) = input {
+                return Err(err);
+            }
+
+            if let Some(result) = self.advance(py)? {
+                return Ok(IterNextOutput::Return(result));
+            }
+        }
+    }
+
+    // Returns the callback's result if it didn't return an awaitable,
+    // otherwise this sets what's being awaited.
+    fn advance(&mut self, py: Python) -> PyResult<Option<PyObject>> {
+        if let Some(descriptors) = self.descriptors.clone() {
+            while let Some((name, injected)) = descriptors.get(self.index) {
+                let index = self.index;
+                self.index += 1;
+                match injected {
+                    Injected::Type(type_) => {
+                        let value = type_.resolve(py, &self.client.borrow(py), &self.ctx)?;
+                        self.kwargs(py).set_item(name, value)?;
+                    }
+                    Injected::Callback(callback) => {
+                        let value = self
+                            .state
+                            .resolve_inline(py, &self.client, &self.ctx, &descriptors, index)?;
+                        match value {
+                            Some(Inline::Value(value)) => {
+                                self.kwargs(py).set_item(name, value.clone_ref(py))?;
+                                self.resolved.push(value);
+                                continue;
+                            }
+                            Some(Inline::Awaitable { key, awaitable }) => {
+                                let iterator = awaitable.call_method0(py, "__await__")?;
+                                self.awaiting = Some(Awaiting::Inline {
+                                    name: name.to_owned(),
+                                    key,
+                                    iterator,
+                                });
+                                return Ok(None);
+                            }
+                            None => {}
+                        }
+
+                        let client = self.client.clone_ref(py);
+                        let fut =
+                            callback.resolve_async(py, client, self.ctx.clone_ref(py), self.state.clone(), name)?;
+                        let iterator = fut_into_coro(py, fut)?.call_method0("__await__")?;
+                        self.awaiting = Some(Awaiting::Dependency(name.to_owned(), iterator.into()));
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+
+        // The callback now owns these resources.
+        CancelToken::release(&self.state.cancel_token, py, self.resolved.iter())?;
+        let args = PyTuple::new(
+            py,
+            std::iter::once(self.callback.as_ref(py)).chain(self.args.as_ref(py).iter()),
+        );
+        let kwargs = self.kwargs.as_ref().map(|kwargs| kwargs.as_ref(py));
+        let result = self.state.contextvars_ctx.as_ref(py).call_method("run", args, kwargs)?;
+        if !is_coroutine(py, result)? {
+            return Ok(Some(result.to_object(py)));
+        }
+
+        self.awaiting = Some(Awaiting::Callback(result.call_method0("__await__")?.into()));
+        Ok(None)
+    }
+}
+
+/// Awaitable which returns a value without suspending.
+#[pyo3::pyclass]
+pub struct Ready {
+    value: Option<PyObject>,
+}
+
+impl Ready {
+    pub fn new(value: PyObject) -> Self {
+        Self { value: Some(value) }
+    }
+}
+
+#[pyo3::pymethods]
+impl Ready {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(&mut self) -> PyResult<IterNextOutput<PyObject, PyObject>> {
+        match self.value.take() {
+            Some(value) => Ok(IterNextOutput::Return(value)),
+            None => Err(PyRuntimeError::new_err("Cannot reuse an already awaited result")),
+        }
+    }
+}
+
+fn to_exception<'p>(type_: &'p PyAny, value: Option<&'p PyAny>) -> PyResult<&'p PyAny> {
+    if type_.is_instance_of::<PyBaseException>()? {
+        return Ok(type_);
+    }
+
+    match value {
+        Some(value) if value.is_instance_of::<PyBaseException>()? => Ok(value),
+        Some(value) if !value.is_none() => type_.call1((value,)),
+        _ => type_.call0(),
+    }
+}
+
+#[pyo3::pymethods]
+impl CallDriver {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python) -> PyResult<IterNextOutput<PyObject, PyObject>> {
+        self.step(py, Input::Send(py.None()))
+    }
+
+    fn send(&mut self, py: Python, value: PyObject) -> PyResult<PyObject> {
+        match self.step(py, Input::Send(value))? {
+            IterNextOutput::Yield(value) => Ok(value),
+            IterNextOutput::Return(value) => Err(PyStopIteration::new_err((value,))),
+        }
+    }
+
+    #[args(type_, value = "None", traceback = "None")]
+    fn throw(
+        &mut self,
+        py: Python,
+        type_: &PyAny,
+        value: Option<&PyAny>,
+        traceback: Option<&PyAny>,
+    ) -> PyResult<PyObject> {
+        let exception = to_exception(type_, value)?;
+        if let Some(traceback) = traceback.filter(|value| !value.is_none()) {
+            exception.setattr("__traceback__", traceback)?;
+        }
+
+        match self.step(py, Input::Throw(PyErr::from_value(exception)))? {
+            IterNextOutput::Yield(value) => Ok(value),
+            IterNextOutput::Return(value) => Err(PyStopIteration::new_err((value,))),
+        }
+    }
+
+    fn close(&mut self, py: Python) -> PyResult<()> {
+        self.done = true;
+        match self.awaiting.take() {
+            Some(
+                Awaiting::Dependency(_, iterator) | Awaiting::Callback(iterator) | Awaiting::Inline { iterator, .. },
+            ) if iterator.as_ref(py).hasattr("close")? => iterator.call_method0(py, "close").map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+}
@@ -30,13 +30,15 @@
 // POSSIBILITY OF SUCH DAMAGE.
 #![allow(clippy::borrow_deref_ref)] // Leads to a ton of false positives around args of py types.
 #![feature(arbitrary_self_types)]
-#![feature(hash_raw_entry)]
 #![feature(once_cell)]
-use client::{BasicContext, Client};
-use pyo3::types::{PyModule, PyType};
-use pyo3::{wrap_pyfunction, PyResult, Python};
+use client::{current_context_var, BasicContext, Client, Overrides};
+use pyo3::types::{IntoPyDict, PyModule, PyType};
+use pyo3::{wrap_pyfunction, PyAny, PyResult, Python};
+use types::{DependencyTimeoutError, RetryPolicy};
 
+mod cancel;
 mod client;
+mod driver;
 mod types;
 mod visitor;
 
@@ -53,6 +55,68 @@ fn patch_alluka(module: &PyModule, py: Python) -> PyResult<()> {
     Ok(())
 }
 
+#[pyo3::pyfunction]
+fn current_context(py: Python) -> PyResult<&PyAny> {
+    current_context_var(py)?.call_method0("get")
+}
+
+
+// Extends alluka's descriptor with the options only this implementation
+// understands.
+const INJECT_CODE: &str = r#"
+class InjectedDescriptor(_types.InjectedDescriptor):
+    __slots__ = ("on_error", "on_error_callback", "on_error_types", "retry", "timeout")
+
+    def __init__(
+        self,
+        *,
+        callback=None,
+        type=None,
+        on_error=UNDEFINED,
+        on_error_callback=None,
+        on_error_types=(Exception,),
+        retry=None,
+        timeout=None,
+    ):
+        super().__init__(callback=callback, type=type)
+        has_fallback = on_error is not UNDEFINED or on_error_callback is not None
+        if callback is None and (has_fallback or retry is not None or timeout is not None):
+            raise ValueError("on_error, on_error_callback, retry and timeout can only be set for callback dependencies")
+
+        if on_error is not UNDEFINED and on_error_callback is not None:
+            raise ValueError("Only one of on_error and on_error_callback can be set")
+
+        # NaN fails both comparisons.
+        if timeout is not None and not 0 < timeout < float("inf"):
+            raise ValueError("timeout must be a positive finite number")
+
+        self.on_error = on_error
+        self.on_error_callback = on_error_callback
+        self.on_error_types = on_error_types
+        self.retry = retry
+        self.timeout = timeout
+
+
+def inject(
+    *,
+    callback=None,
+    type=None,
+    on_error=UNDEFINED,
+    on_error_callback=None,
+    on_error_types=(Exception,),
+    retry=None,
+    timeout=None,
+):
+    return InjectedDescriptor(
+        callback=callback,
+        type=type,
+        on_error=on_error,
+        on_error_callback=on_error_callback,
+        on_error_types=on_error_types,
+        retry=retry,
+        timeout=timeout,
+    )
+"#;
 
 #[pyo3::pymodule]
 fn alluka_rust(py: Python, module: &PyModule) -> PyResult<()> {
@@ -79,8 +143,24 @@ fn alluka_rust(py: Python, module: &PyModule) -> PyResult<()> {
     module.add("__version__", "0.1.0")?;
     module.add_class::<Client>()?;
     module.add_class::<BasicContext>()?;
+    module.add_class::<Overrides>()?;
+    module.add_class::<RetryPolicy>()?;
+    module.add("DependencyTimeoutError", py.get_type::<DependencyTimeoutError>())?;
+    module.add_function(wrap_pyfunction!(current_context, module)?)?;
     module.add_function(wrap_pyfunction!(patch_alluka, module)?)?;
 
+    let globals_ = [
+        ("_types", py.import("alluka._types")?),
+        ("UNDEFINED", abc.getattr("UNDEFINED")?),
+    ]
+    .into_py_dict(py);
+    py.run(INJECT_CODE, Some(globals_), None)?;
+    for name in ["InjectedDescriptor", "inject"] {
+        let value = globals_.get_item(name).unwrap();
+        value.setattr("__module__", "alluka_rust")?;
+        module.add(name, value)?;
+    }
+
     abc.getattr("Client")?
         .call_method1("register", (PyType::new::<Client>(py),))?;
 
@@ -28,52 +28,516 @@
 // CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE)
 // ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
 // POSSIBILITY OF SUCH DAMAGE.
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use pyo3::exceptions::{PyAttributeError, PyException, PyTimeoutError, PyValueError};
 use pyo3::types::PyTuple;
 use pyo3::{IntoPy, Py, PyAny, PyErr, PyObject, PyRef, PyResult, Python, ToPyObject};
+use pyo3_anyio::tokio::await_py1;
 
-use crate::client::{BasicContext, Client};
+use crate::cancel::{cancelled_error, until_cancelled, CancelToken};
+use crate::client::{is_coroutine, undefined, CallState, Client, Context};
 
+pyo3::import_exception!(alluka._errors, AsyncOnlyError);
 pyo3::import_exception!(alluka._errors, MissingDependencyError);
+pyo3::create_exception!(
+    alluka_rust,
+    DependencyTimeoutError,
+    PyTimeoutError,
+    "Error raised when resolving an async callback dependency times out."
+);
 
 pub type InjectedTuple = (String, Injected);
 
+static ANYIO_SLEEP: OnceLock<PyObject> = OnceLock::new();
+
+fn import_anyio_sleep(py: Python) -> PyResult<&PyAny> {
+    ANYIO_SLEEP
+        .get_or_try_init(|| Ok(py.import("anyio")?.getattr("sleep")?.to_object(py)))
+        .map(|value| value.as_ref(py))
+}
+
+fn add_note(py: Python, err: &PyErr, note: String) -> PyResult<()> {
+    let value = err.value(py);
+    // BaseException.add_note was only added in Python 3.11.
+    if value.hasattr("add_note")? {
+        value.call_method1("add_note", (note,))?;
+    } else if let Ok(notes) = value.getattr("__notes__") {
+        notes.call_method1("append", (note,))?;
+    } else {
+        value.setattr("__notes__", vec![note])?;
+    }
+
+    Ok(())
+}
+
+// The longest delay between retries in seconds, which keeps the delay within
+// the range of a Duration.
+const MAX_BACKOFF: f64 = 86_400.0;
+// Bounds the exponent so the factor stays finite and a zero backoff stays zero.
+const MAX_BACKOFF_DOUBLINGS: u32 = 64;
+
+/// Policy used to retry a callback dependency which raised.
+#[pyo3::pyclass]
+pub struct RetryPolicy {
+    #[pyo3(get)]
+    attempts: u32,
+    #[pyo3(get)]
+    backoff: f64,
+    #[pyo3(get)]
+    on: PyObject,
+    // Alluka's own errors aren't retried unless `on` was explicitly passed, as
+    // these won't go away by calling the dependency again.
+    default_on: bool,
+}
+
+impl RetryPolicy {
+    fn retries(&self, py: Python, err: &PyErr) -> bool {
+        err.matches(py, self.on.as_ref(py))
+            && !(self.default_on
+                && (err.is_instance_of::<AsyncOnlyError>(py) || err.is_instance_of::<MissingDependencyError>(py)))
+    }
+}
+
+#[pyo3::pymethods]
+impl RetryPolicy {
+    #[new]
+    #[args(attempts, backoff = "0.0", on = "None")]
+    fn new(py: Python, attempts: u32, backoff: f64, on: Option<PyObject>) -> PyResult<Self> {
+        if attempts == 0 {
+            return Err(PyValueError::new_err("attempts must be greater than 0"));
+        }
+
+        // NaN fails this comparison.
+        if !(backoff.is_finite() && backoff >= 0.0) {
+            return Err(PyValueError::new_err("backoff must be a non-negative finite number"));
+        }
+
+        Ok(Self {
+            attempts,
+            backoff,
+            default_on: on.is_none(),
+            on: on.unwrap_or_else(|| (py.get_type::<PyException>(),).to_object(py)),
+        })
+    }
+
+    fn __repr__(&self, py: Python) -> PyResult<String> {
+        Ok(format!(
+            "RetryPolicy(attempts={}, backoff={}, on={})",
+            self.attempts,
+            self.backoff,
+            self.on.as_ref(py).repr()?
+        ))
+    }
+}
+
+// Tracks the failed attempts for a single resolution of a callback dependency.
+struct RetryState {
+    failures: Vec<String>,
+    policy: Option<Py<RetryPolicy>>,
+}
+
+impl RetryState {
+    fn new(py: Python, options: &CallbackOptions) -> Self {
+        Self {
+            failures: Vec::new(),
+            policy: options.retry.as_ref().map(|policy| policy.clone_ref(py)),
+        }
+    }
+
+    // Returns how many seconds to wait before the next attempt or the error
+    // which should be raised if the dependency shouldn't be retried.
+    fn next_delay(&mut self, py: Python, err: PyErr) -> PyResult<f64> {
+        let policy = match self.policy.as_ref() {
+            Some(policy) => policy.borrow(py),
+            None => return Err(err),
+        };
+
+        self.failures.push(format!(
+            "Attempt {}: {}",
+            self.failures.len() + 1,
+            err.value(py).repr()?
+        ));
+        let attempt = self.failures.len() as u32;
+        // The backoff doubles after each retry up to MAX_BACKOFF.
+        if attempt < policy.attempts && policy.retries(py, &err) {
+            let factor = 2_f64.powi(attempt.saturating_sub(1).min(MAX_BACKOFF_DOUBLINGS) as i32);
+            return Ok((policy.backoff * factor).min(MAX_BACKOFF));
+        }
+
+        if attempt > 1 {
+            add_note(
+                py,
+                &err,
+                format!(
+                    "Dependency failed after {} attempts:\n{}",
+                    attempt,
+                    self.failures.join("\n")
+                ),
+            )?;
+        }
+
+        Err(err)
+    }
+}
+
+/// Value or callback used when a callback dependency raises.
+pub struct Fallback {
+    // Whether value is an `on_error_callback` which is called with DI.
+    is_callback: bool,
+    on: PyObject,
+    value: PyObject,
+}
+
+impl Fallback {
+    fn clone_ref(&self, py: Python) -> Self {
+        Self {
+            is_callback: self.is_callback,
+            on: self.on.clone_ref(py),
+            value: self.value.clone_ref(py),
+        }
+    }
+
+    // Returns the error if there's no fallback for it.
+    fn find<'a>(py: Python, fallback: Option<&'a Self>, err: PyErr) -> PyResult<&'a Self> {
+        match fallback {
+            Some(fallback) if err.matches(py, fallback.on.as_ref(py)) => Ok(fallback),
+            _ => Err(err),
+        }
+    }
+}
+
+/// Extra options which can be set on a callback dependency through
+/// `alluka_rust.inject`.
+#[derive(Default)]
+pub struct CallbackOptions {
+    pub fallback: Option<Fallback>,
+    pub retry: Option<Py<RetryPolicy>>,
+    pub timeout: Option<f64>,
+}
+
+impl CallbackOptions {
+    pub fn from_descriptor(py: Python, descriptor: &PyAny) -> PyResult<Self> {
+        // Descriptors created by alluka.inject won't have these attributes.
+        let get = |name: &str| match descriptor.getattr(name) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if err.is_instance_of::<PyAttributeError>(py) => Ok(None),
+            Err(err) => Err(err),
+        };
+
+        let undefined = undefined(py)?;
+        let value = get("on_error")?.filter(|value| !value.is(&undefined));
+        let callback = get("on_error_callback")?.filter(|value| !value.is_none());
+        let fallback = match (value, callback) {
+            (Some(_), Some(_)) => {
+                return Err(PyValueError::new_err(
+                    "Only one of on_error and on_error_callback can be set",
+                ))
+            }
+            (Some(value), None) => Some((false, value)),
+            (None, Some(callback)) => Some((true, callback)),
+            (None, None) => None,
+        }
+        .map(|(is_callback, value)| {
+            Ok::<_, PyErr>(Fallback {
+                is_callback,
+                on: match get("on_error_types")? {
+                    Some(on) => on.to_object(py),
+                    None => (py.get_type::<PyException>(),).to_object(py),
+                },
+                value: value.to_object(py),
+            })
+        })
+        .transpose()?;
+
+        Ok(Self {
+            fallback,
+            retry: get("retry")?.map(|value| value.extract()).transpose()?.flatten(),
+            timeout: get("timeout")?.map(|value| value.extract()).transpose()?.flatten(),
+        })
+    }
+}
+
 pub struct InjectedCallback {
     pub callback: PyObject,
+    pub options: CallbackOptions,
+    // Whether the callback isn't a coroutine function and only has type
+    // dependencies. This is lazily set from the callback's descriptors, which
+    // don't depend on the client's registrations.
+    sync_leaf: OnceLock<bool>,
 }
 
-impl InjectedCallback {
-    pub fn resolve<'p>(
-        &'p self,
+/// Where the value of a callback dependency came from.
+pub enum Resolved<'p> {
+    /// The context's result cache.
+    Cached(&'p PyAny),
+    /// A fallback value, which is shared rather than owned by the call.
+    Fallback(&'p PyAny),
+    /// Freshly returned by the callback (or its fallback callback).
+    Fresh(&'p PyAny),
+}
+
+impl<'p> Resolved<'p> {
+    /// Cache the value, passing fresh values to the call's cancel token (if
+    /// any) so it can decide when to cache them.
+    ///
+    /// This errors without caching anything if the call was cancelled as a
+    /// result which belongs to a cancelled call shouldn't outlive it.
+    pub fn settle(
+        self,
         py: Python<'p>,
-        client: &'p PyRef<'p, Client>,
-        ctx: &'p PyRef<'p, BasicContext>,
+        ctx: &Context,
+        key: &PyAny,
+        token: Option<&Py<CancelToken>>,
     ) -> PyResult<&'p PyAny> {
+        if token.map_or(false, |token| token.borrow(py).is_cancelled()) {
+            return Err(cancelled_error());
+        }
+
+        match (self, token) {
+            (Self::Cached(value), _) => Ok(value),
+            (Self::Fresh(value), Some(token)) => {
+                CancelToken::track(token, py, ctx, key, value)?;
+                Ok(value)
+            }
+            (Self::Fallback(value) | Self::Fresh(value), _) => {
+                ctx.cache_result(py, key, value.to_object(py))?;
+                Ok(value)
+            }
+        }
+    }
+}
+
+impl InjectedCallback {
+    pub fn resolve<'p>(&self, py: Python<'p>, client: &PyRef<'p, Client>, ctx: &Context) -> PyResult<&'p PyAny> {
+        let (callback, resolved) = self.resolve_uncached(py, client, ctx)?;
+        resolved.settle(py, ctx, callback, None)
+    }
+
+    // Returns the callback (after overrides) which the value should be cached
+    // under alongside the value.
+    pub fn resolve_uncached<'p>(
+        &self,
+        py: Python<'p>,
+        client: &PyRef<'p, Client>,
+        ctx: &Context,
+    ) -> PyResult<(&'p PyAny, Resolved<'p>)> {
+        let callback = ctx
+            .get_callback_override(py, client, self.callback.as_ref(py))?
+            .unwrap_or_else(|| self.callback.clone_ref(py))
+            .into_ref(py);
+
+        if let Some(result) = ctx.get_cached_result(py, callback)? {
+            return Ok((callback, Resolved::Cached(result.into_ref(py))));
+        }
+
+        let mut retries = RetryState::new(py, &self.options);
+        let result = loop {
+            match client.call_with_ctx_rust(py, ctx, callback, PyTuple::empty(py), None) {
+                Ok(result) => break Resolved::Fresh(result),
+                Err(err) => match retries.next_delay(py, err) {
+                    Ok(delay) => py.allow_threads(|| std::thread::sleep(Duration::from_secs_f64(delay))),
+                    Err(err) => {
+                        let fallback = Fallback::find(py, self.options.fallback.as_ref(), err)?;
+                        if !fallback.is_callback {
+                            break Resolved::Fallback(fallback.value.as_ref(py));
+                        }
+
+                        let fallback = fallback.value.as_ref(py);
+                        let result = client.call_with_ctx_rust(py, ctx, fallback, PyTuple::empty(py), None)?;
+                        break Resolved::Fresh(result);
+                    }
+                },
+            }
+        };
+        Ok((callback, result))
+    }
+
+    /// Whether this callback dependency can be resolved with
+    /// [InjectedCallback::resolve_inline] on the async path rather than going
+    /// through tokio.
+    pub fn resolves_inline(&self, py: Python, client: &Client, ctx: &Context) -> PyResult<bool> {
+        // Retry backoffs have to be awaited, while fallbacks and timeouts would
+        // also have to cover awaiting a coroutine returned by a plain function.
+        if self.options.retry.is_some()
+            || self.options.fallback.is_some()
+            || self.options.timeout.or(client.resolution_timeout).is_some()
+        {
+            return Ok(false);
+        }
+
+        // Overrides may be async.
         let callback = self.callback.as_ref(py);
-        if let Some(callback) = client.get_callback_override(py, callback)? {
-            ctx.call_with_di_rust(py, client, callback, PyTuple::empty(py), None)
-        } else {
-            ctx.call_with_di_rust(py, client, callback, PyTuple::empty(py), None)
+        if let Some(value) = ctx.get_callback_override(py, client, callback)? {
+            if !value.is(callback) {
+                return Ok(false);
+            }
         }
+
+        if let Some(sync_leaf) = self.sync_leaf.get() {
+            return Ok(*sync_leaf);
+        }
+
+        let descriptors = client.build_descriptors(py, callback)?;
+        let sync_leaf = !descriptors.is_async()
+            && descriptors
+                .iter()
+                .all(|(_, injected)| matches!(injected, Injected::Type(_)));
+        Ok(*self.sync_leaf.get_or_init(|| sync_leaf))
+    }
+
+    /// Resolve a callback dependency which [InjectedCallback::resolves_inline].
+    ///
+    /// This returns the value and whether it's a coroutine the caller still has
+    /// to await, as functions which aren't coroutine functions may still return
+    /// one. Coroutines aren't cached.
+    pub fn resolve_inline<'p>(
+        &self,
+        py: Python<'p>,
+        client: &PyRef<'p, Client>,
+        ctx: &Context,
+        token: &Py<CancelToken>,
+    ) -> PyResult<(&'p PyAny, bool)> {
+        let callback = self.callback.as_ref(py);
+        if let Some(result) = ctx.get_cached_result(py, callback)? {
+            return Ok((result.into_ref(py), false));
+        }
+
+        let result = client.call_with_ctx_unchecked(py, ctx, callback, PyTuple::empty(py), None)?;
+        if is_coroutine(py, result)? {
+            return Ok((result, true));
+        }
+
+        Ok((Resolved::Fresh(result).settle(py, ctx, callback, Some(token))?, false))
     }
 
     pub fn resolve_async(
         &self,
         py: Python,
         client: Py<Client>,
-        ctx: Py<BasicContext>,
-    ) -> PyResult<std::pin::Pin<Box<dyn std::future::Future<Output = PyResult<PyObject>> + Send>>> {
-        let args = PyTuple::empty(py).into_py(py);
+        ctx: Context,
+        state: Arc<CallState>,
+        name: &str,
+    ) -> PyResult<Pin<Box<dyn Future<Output = PyResult<PyObject>> + Send>>> {
         let client_borrow = client.borrow(py);
-        let other_callback = client_borrow
-            .get_callback_override(py, self.callback.as_ref(py))?
-            .map(|value| value.to_object(py));
+        let callback = ctx
+            .get_callback_override(py, &client_borrow, self.callback.as_ref(py))?
+            .unwrap_or_else(|| self.callback.clone_ref(py));
+
+        if let Some(result) = ctx.get_cached_result(py, callback.as_ref(py))? {
+            return Ok(Box::pin(std::future::ready(Ok(result))));
+        }
+
+        let timeout = self.options.timeout.or(client_borrow.resolution_timeout);
         drop(client_borrow);
-        let result = if let Some(callback) = other_callback {
-            BasicContext::call_with_async_di_rust(ctx, client, callback, args, None)
-        } else {
-            BasicContext::call_with_async_di_rust(ctx, client, self.callback.clone_ref(py), args, None)
+        let in_flight = ctx.get_in_flight(py, callback.as_ref(py))?;
+        let in_flight_ctx = ctx.clone_ref(py);
+        let in_flight_key = callback.clone_ref(py);
+        let cache_ctx = ctx.clone_ref(py);
+        let cache_key = callback.clone_ref(py);
+        let fallback = self.options.fallback.as_ref().map(|fallback| fallback.clone_ref(py));
+        let fallback_args = (client.clone_ref(py), ctx.clone_ref(py), state.clone());
+        let token = state.cancel_token.clone_ref(py);
+        let name = name.to_owned();
+        let attempt = move |py: Python| -> PyResult<Pin<Box<dyn Future<Output = PyResult<PyObject>> + Send>>> {
+            let client = client.clone_ref(py);
+            let ctx = ctx.clone_ref(py);
+            let callback = callback.clone_ref(py);
+            Ok(match timeout {
+                Some(timeout) => Box::pin(Client::call_with_timeout_async(
+                    client, py, ctx, &state, timeout, &name, callback,
+                )?),
+                None => {
+                    let args = PyTuple::empty(py).into_py(py);
+                    Box::pin(Client::call_with_ctx_async_rust(
+                        client,
+                        ctx,
+                        state.clone(),
+                        callback,
+                        args,
+                        None,
+                    ))
+                }
+            })
+        };
+
+        let mut retries = RetryState::new(py, &self.options);
+        let result: Pin<Box<dyn Future<Output = PyResult<PyObject>> + Send>> = Box::pin(async move {
+            let result = loop {
+                let err = match Python::with_gil(&attempt)?.await {
+                    Ok(result) => break Ok((result, true)),
+                    Err(err) => err,
+                };
+
+                if Python::with_gil(|py| token.borrow(py).is_cancellation(py, &err)) {
+                    return Err(err);
+                }
+
+                match Python::with_gil(|py| retries.next_delay(py, err)) {
+                    Ok(delay) => {
+                        let sleep = Python::with_gil(|py| {
+                            await_py1(import_anyio_sleep(py)?, &[delay.into_py(py).into_ref(py)])
+                        })?;
+                        until_cancelled(&token, sleep).await?;
+                    }
+                    Err(err) => break Err(err),
+                }
+            };
+
+            let (result, fresh) = match result {
+                Ok(result) => result,
+                Err(err) => {
+                    let (client, ctx, state) = fallback_args;
+                    let fallback = Python::with_gil(|py| {
+                        let fallback = Fallback::find(py, fallback.as_ref(), err)?;
+                        Ok::<_, PyErr>((fallback.is_callback, fallback.value.clone_ref(py)))
+                    })?;
+                    match fallback {
+                        (true, callback) => {
+                            let args = Python::with_gil(|py| PyTuple::empty(py).into_py(py));
+                            let result =
+                                Client::call_with_ctx_async_rust(client, ctx, state, callback, args, None).await?;
+                            (result, true)
+                        }
+                        (false, value) => (value, false),
+                    }
+                }
+            };
+
+            Python::with_gil(|py| {
+                let resolved = if fresh {
+                    Resolved::Fresh(result.as_ref(py))
+                } else {
+                    Resolved::Fallback(result.as_ref(py))
+                };
+                resolved.settle(py, &cache_ctx, cache_key.as_ref(py), Some(&token))?;
+                Ok(result)
+            })
+        });
+
+        let in_flight = match in_flight {
+            Some(in_flight) => in_flight,
+            None => return Ok(result),
         };
-        Ok(Box::pin(result))
+
+        // Concurrent resolutions of the same callback on the context share the
+        // first resolution's result rather than each calling the callback.
+        Ok(Box::pin(async move {
+            let result = in_flight
+                .get_or_init(|| async move { result.await.map_err(|err| Python::with_gil(|py| err.into_py(py))) })
+                .await;
+
+            Python::with_gil(|py| {
+                in_flight_ctx.remove_in_flight(py, in_flight_key.as_ref(py), &in_flight)?;
+                match result {
+                    Ok(value) => Ok(value.clone_ref(py)),
+                    Err(err) => Err(PyErr::from_value(err.as_ref(py))),
+                }
+            })
+        }))
     }
 }
 
@@ -81,27 +545,31 @@ impl InjectedCallback {
 pub struct InjectedType {
     default: Option<PyObject>,
     repr_type: PyObject,
-    type_ids: Vec<isize>,
+    types: Vec<(isize, PyObject)>,
 }
 
 impl InjectedType {
-    pub fn resolve<'p>(
-        &'p self,
-        py: Python<'p>,
-        client: &'p PyRef<'p, Client>,
-        ctx: &'p PyRef<'p, BasicContext>,
-    ) -> PyResult<&'p PyAny> {
-        if let Some(value) = self
-            .type_ids
-            .iter()
-            .filter_map(|cls| ctx.get_type_dependency_rust(client, cls))
-            .next()
-        {
-            return Ok(value.as_ref(py));
+    // This ignores the context so is only valid for contexts which don't
+    // special-case any types.
+    pub fn resolve_from_client(&self, py: Python, client: &Client) -> PyResult<Option<PyObject>> {
+        for (type_id, type_) in self.types.iter() {
+            if let Some(value) = client.get_type_dependency_rust(py, type_id, type_.as_ref(py))? {
+                return Ok(Some(value));
+            }
+        }
+
+        Ok(self.default.as_ref().map(|default| default.clone_ref(py)))
+    }
+
+    pub fn resolve<'p>(&self, py: Python<'p>, client: &PyRef<'p, Client>, ctx: &Context) -> PyResult<&'p PyAny> {
+        for (type_id, type_) in self.types.iter() {
+            if let Some(value) = ctx.get_type_dependency(py, client, type_id, type_.as_ref(py))? {
+                return Ok(value.into_ref(py));
+            }
         }
 
         if let Some(default) = self.default.as_ref() {
-            return Ok(default.as_ref(py));
+            return Ok(default.clone_ref(py).into_ref(py));
         }
 
         Err(PyErr::new::<MissingDependencyError, _>((
@@ -121,9 +589,11 @@ pub enum Injected {
 }
 
 impl Injected {
-    pub fn new_callback(py: Python, callback: &PyAny) -> Self {
+    pub fn new_callback(py: Python, callback: &PyAny, options: CallbackOptions) -> Self {
         Injected::Callback(InjectedCallback {
             callback: callback.to_object(py),
+            options,
+            sync_leaf: OnceLock::new(),
         })
     }
 
@@ -131,10 +601,10 @@ impl Injected {
         Ok(Injected::Type(InjectedType {
             default: default.map(|value| value.to_object(py)),
             repr_type: repr_type.to_object(py),
-            type_ids: types
+            types: types
                 .into_iter()
-                .map(|type_| type_.hash())
-                .collect::<PyResult<Vec<isize>>>()?,
+                .map(|type_| Ok((type_.hash()?, type_.to_object(py))))
+                .collect::<PyResult<Vec<(isize, PyObject)>>>()?,
         }))
     }
 }
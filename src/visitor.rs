@@ -33,12 +33,12 @@ use std::collections::HashMap;
 use std::rc::Rc;
 use std::sync::OnceLock;
 
-use pyo3::exceptions::{PyKeyError, PyValueError};
+use pyo3::exceptions::{PyAttributeError, PyKeyError, PyValueError};
 use pyo3::type_object::PyTypeObject;
-use pyo3::types::{IntoPyDict, PyMapping, PyString, PyTuple};
-use pyo3::{FromPyObject, IntoPy, PyAny, PyErr, PyObject, PyResult, Python, ToPyObject};
+use pyo3::types::{IntoPyDict, PyDict, PyMapping, PyString};
+use pyo3::{AsPyPointer, PyAny, PyErr, PyObject, PyResult, Python, ToPyObject};
 
-use crate::types::{Injected, InjectedTuple};
+use crate::types::{CallbackOptions, Injected, InjectedTuple};
 
 pyo3::import_exception!(alluka._types, InjectedDescriptor);
 
@@ -95,13 +95,32 @@ impl Node for Annotation {
 
 pub(crate) struct Callback {
     callback: PyObject,
-    pub empty: PyObject,
+    // Only set when the signature was parsed natively.
+    globals: Option<PyObject>,
     resolved: OnceCell<()>,
-    pub signature: RefCell<Option<HashMap<String, PyObject>>>,
+    pub signature: RefCell<Option<HashMap<String, Parameter>>>,
 }
 
-fn _inspect(py: Python, callback: &PyAny, eval_str: bool) -> PyResult<Option<HashMap<String, PyObject>>> {
-    let signature: PyResult<Option<HashMap<String, PyObject>>> = import_inspect(py)?
+#[derive(Clone)]
+pub(crate) struct Parameter {
+    pub annotation: Option<PyObject>,
+    pub default: Option<PyObject>,
+    pub positional_only: bool,
+}
+
+fn _inspect(py: Python, callback: &PyAny, eval_str: bool) -> PyResult<Option<HashMap<String, Parameter>>> {
+    let parameter_type = import_inspect(py)?.getattr("Parameter")?;
+    let empty = parameter_type.getattr("empty")?;
+    let positional_only = parameter_type.getattr("POSITIONAL_ONLY")?;
+    let non_empty = |value: &PyAny| {
+        if value.is(empty) {
+            None
+        } else {
+            Some(value.to_object(py))
+        }
+    };
+
+    let signature: PyResult<Option<HashMap<String, Parameter>>> = import_inspect(py)?
         .call_method(
             "signature",
             (callback,),
@@ -115,9 +134,12 @@ fn _inspect(py: Python, callback: &PyAny, eval_str: bool) -> PyResult<Option<Has
                 .items()?
                 .iter()?
                 .map(|entry| {
-                    entry
-                        .and_then(|value| value.cast_as::<PyTuple>().map_err(PyErr::from))
-                        .and_then(|value| Ok((String::extract(value.get_item(0)?)?, value.get_item(1)?.into_py(py))))
+                    let (name, parameter) = entry?.extract::<(String, &PyAny)>()?;
+                    Ok((name, Parameter {
+                        annotation: non_empty(parameter.getattr("annotation")?),
+                        default: non_empty(parameter.getattr("default")?),
+                        positional_only: parameter.getattr("kind")?.is(positional_only),
+                    }))
                 })
                 .collect()
         })
@@ -129,18 +151,211 @@ fn _inspect(py: Python, callback: &PyAny, eval_str: bool) -> PyResult<Option<Has
     }
 }
 
+const CO_VARARGS: u32 = 0x04;
+const CO_VARKEYWORDS: u32 = 0x08;
+
+/// Returns the plain function behind a callback and whether it's a bound method
+/// or [None] if its signature can't be read straight from the function.
+pub fn unwrap_function(py: Python, callback: &PyAny) -> PyResult<Option<(&PyAny, bool)>> {
+    let types = import_type(py)?;
+    let function_type = types.getattr("FunctionType")?;
+    let (function, is_method) = if callback.get_type().is(function_type) {
+        (callback, false)
+    } else if callback.get_type().is(types.getattr("MethodType")?) {
+        (callback.getattr("__func__")?, true)
+    } else {
+        return Ok(None);
+    };
+
+    // inspect.signature follows __wrapped__ and respects __signature__.
+    if !function.get_type().is(function_type)
+        || callback.hasattr("__signature__")?
+        || function.hasattr("__wrapped__")?
+    {
+        return Ok(None);
+    }
+
+    Ok(Some((function, is_method)))
+}
+
+/// Whether a callback is known to return a coroutine.
+pub fn is_coroutine_function(py: Python, callback: &PyAny) -> PyResult<bool> {
+    import_inspect(py)?
+        .call_method1("iscoroutinefunction", (callback,))?
+        .is_true()
+}
+
+// This reads the signature straight from a plain function's (or bound method's)
+// code object and returns [None] if inspect has to be used.
+fn _parse_native(py: Python, callback: &PyAny) -> PyResult<Option<(HashMap<String, Parameter>, PyObject)>> {
+    let (function, is_method) = match unwrap_function(py, callback)? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    let code = function.getattr("__code__")?;
+    let arg_count = code.getattr("co_argcount")?.extract::<usize>()?;
+    let positional_only_count = code.getattr("co_posonlyargcount")?.extract::<usize>()?;
+    let keyword_only_count = code.getattr("co_kwonlyargcount")?.extract::<usize>()?;
+    let flags = code.getattr("co_flags")?.extract::<u32>()?;
+    let names = code.getattr("co_varnames")?.extract::<Vec<String>>()?;
+    let defaults = function
+        .getattr("__defaults__")?
+        .extract::<Option<Vec<PyObject>>>()?
+        .unwrap_or_default();
+    // __defaults__ can be reassigned to a tuple longer than the parameters it
+    // covers, which is left to inspect.
+    if defaults.len() > arg_count {
+        return Ok(None);
+    }
+
+    let mut keyword_defaults = function
+        .getattr("__kwdefaults__")?
+        .extract::<Option<HashMap<String, PyObject>>>()?
+        .unwrap_or_default();
+    let annotations = function
+        .getattr("__annotations__")?
+        .extract::<HashMap<String, PyObject>>()?;
+    let mut parameters = HashMap::with_capacity(names.len());
+    let mut add_parameter = |name: &String, default: Option<PyObject>, positional_only: bool| {
+        let annotation = annotations.get(name).map(|value| value.clone_ref(py));
+        parameters.insert(name.to_owned(), Parameter {
+            annotation,
+            default,
+            positional_only,
+        });
+    };
+
+    let first_default = arg_count - defaults.len();
+    let mut defaults = defaults.into_iter();
+    for (index, name) in names[..arg_count].iter().enumerate() {
+        let default = if index >= first_default { defaults.next() } else { None };
+        // Bound methods don't include their first parameter (e.g. self).
+        if !(is_method && index == 0) {
+            add_parameter(name, default, index < positional_only_count);
+        }
+    }
+
+    let mut index = arg_count + keyword_only_count;
+    for name in names[arg_count..index].iter() {
+        add_parameter(name, keyword_defaults.remove(name), false);
+    }
+
+    for flag in [CO_VARARGS, CO_VARKEYWORDS] {
+        if flags & flag != 0 {
+            add_parameter(&names[index], None, false);
+            index += 1;
+        }
+    }
+
+    Ok(Some((parameters, function.getattr("__globals__")?.to_object(py))))
+}
+
+/// Structural identity of a function's signature.
+///
+/// This lets functions which share a code object (e.g. closures created per
+/// request) share descriptors, with the code object itself being compared
+/// separately. Leaf objects are compared by identity and are kept alive by the
+/// fingerprint so their ids can't be reused.
+pub(crate) enum Fingerprint {
+    Object(PyObject),
+    Node(Vec<Fingerprint>),
+}
+
+impl PartialEq for Fingerprint {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Object(left), Self::Object(right)) => left.as_ptr() == right.as_ptr(),
+            (Self::Node(left), Self::Node(right)) => left == right,
+            _ => false,
+        }
+    }
+}
+
+impl Fingerprint {
+    /// Fingerprint a function returned by [unwrap_function].
+    pub fn new(py: Python, function: &PyAny, is_method: bool) -> PyResult<Self> {
+        let mut nodes = vec![
+            Self::object(function.getattr("__globals__")?),
+            Self::object(is_method.to_object(py).into_ref(py)),
+        ];
+        let defaults = function.getattr("__defaults__")?;
+        if !defaults.is_none() {
+            for value in defaults.iter()? {
+                nodes.push(Self::from_value(py, value?)?);
+            }
+        }
+
+        for name in ["__kwdefaults__", "__annotations__"] {
+            let mapping = function.getattr(name)?;
+            if mapping.is_none() {
+                continue;
+            }
+
+            for entry in mapping.cast_as::<PyDict>()?.iter() {
+                nodes.push(Self::object(entry.0));
+                nodes.push(Self::from_value(py, entry.1)?);
+            }
+        }
+
+        Ok(Self::Node(nodes))
+    }
+
+    fn object(value: &PyAny) -> Self {
+        Self::Object(value.into())
+    }
+
+    // Descriptors and typing generics are compared by their contents since a
+    // new instance is usually created each time the function is defined.
+    fn from_value(py: Python, value: &PyAny) -> PyResult<Self> {
+        if value.is_instance_of::<InjectedDescriptor>()? {
+            let mut nodes = vec![Self::object(value.get_type())];
+            for name in [
+                "callback",
+                "type",
+                "on_error",
+                "on_error_callback",
+                "on_error_types",
+                "retry",
+                "timeout",
+            ] {
+                match value.getattr(name) {
+                    Ok(value) => nodes.push(Self::from_value(py, value)?),
+                    Err(err) if err.is_instance_of::<PyAttributeError>(py) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+
+            return Ok(Self::Node(nodes));
+        }
+
+        let typing = import_typing(py)?;
+        let origin = typing.call_method1("get_origin", (value,))?;
+        if origin.is_none() {
+            return Ok(Self::object(value));
+        }
+
+        let mut nodes = vec![Self::object(origin)];
+        for arg in typing.call_method1("get_args", (value,))?.iter()? {
+            nodes.push(Self::from_value(py, arg?)?);
+        }
+
+        Ok(Self::Node(nodes))
+    }
+}
+
 impl Callback {
     pub fn new(py: Python, callback: &PyAny) -> PyResult<Self> {
-        let empty = import_inspect(py)?
-            .getattr("Parameter")?
-            .getattr("empty")?
-            .to_object(py);
+        let (signature, globals) = match _parse_native(py, callback)? {
+            Some((signature, globals)) => (Some(signature), Some(globals)),
+            None => (_inspect(py, callback, false)?, None),
+        };
 
         Ok(Self {
             callback: callback.to_object(py),
-            empty,
+            globals,
             resolved: OnceCell::new(),
-            signature: RefCell::new(_inspect(py, callback, false)?),
+            signature: RefCell::new(signature),
         })
     }
 
@@ -149,34 +364,48 @@ impl Callback {
     }
 
     pub fn resolve_annotation(&self, py: Python, name: &str) -> PyResult<Option<PyObject>> {
-        let signature = self.signature.borrow();
-        if signature.is_none() {
-            return Ok(None);
-        }
+        let annotation = match self.signature.borrow().as_ref() {
+            Some(signature) => signature
+                .get(name)
+                .ok_or_else(|| PyKeyError::new_err(name.to_owned()))?
+                .annotation
+                .as_ref()
+                .map(|annotation| annotation.clone_ref(py)),
+            None => return Ok(None),
+        };
 
-        let parameter = signature
-            .as_ref()
-            .unwrap()
-            .get(name)
-            .map(|p| p.getattr(py, "annotation"));
-
-        drop(signature);
-        match parameter {
-            Some(Ok(ref annotation)) => {
-                if annotation.is(&self.empty) {
-                    return Ok(None);
-                }
+        let annotation = match annotation {
+            Some(annotation) if annotation.as_ref(py).is_instance_of::<PyString>()? => annotation,
+            other => return Ok(other),
+        };
 
-                if self.resolved.get().is_none() && annotation.as_ref(py).is_instance_of::<PyString>()? {
-                    self.signature.replace(_inspect(py, self.callback.as_ref(py), true)?);
-                    self.resolved.set(()).unwrap();
-                    self.resolve_annotation(py, name)
-                } else {
-                    Ok(Some(annotation.clone_ref(py)))
+        match self.globals.as_ref() {
+            // Natively parsed signatures only evaluate the annotations which are used.
+            Some(globals) => {
+                let annotation = py
+                    .eval(
+                        annotation.extract(py)?,
+                        Some(globals.as_ref(py).cast_as::<PyDict>()?),
+                        None,
+                    )?
+                    .to_object(py);
+                if let Some(parameter) = self
+                    .signature
+                    .borrow_mut()
+                    .as_mut()
+                    .and_then(|value| value.get_mut(name))
+                {
+                    parameter.annotation = Some(annotation.clone_ref(py));
                 }
+
+                Ok(Some(annotation))
             }
-            Some(Err(err)) => Err(err),
-            None => Err(PyKeyError::new_err(name.to_owned())),
+            None if self.resolved.get().is_none() => {
+                self.signature.replace(_inspect(py, self.callback.as_ref(py), true)?);
+                self.resolved.set(()).unwrap();
+                self.resolve_annotation(py, name)
+            }
+            None => Ok(Some(annotation)),
         }
     }
 }
@@ -197,13 +426,9 @@ impl Node for Default {
             .unwrap()
             .get(&name)
             .ok_or_else(|| PyKeyError::new_err(name.clone()))?
-            .getattr(py, "default")?;
-
-        let default = if default.is(&callback.empty) {
-            None
-        } else {
-            Some(default)
-        };
+            .default
+            .as_ref()
+            .map(|default| default.clone_ref(py));
 
         Ok(Self {
             default,
@@ -280,22 +505,17 @@ impl Visitor for ParameterVisitor {
             Some(annotation) => annotation,
             None => return Ok(None),
         };
-        let parameter = node
+        let default = node
             .callback
             .signature
             .borrow()
             .as_ref()
             .unwrap()
             .get(&node.name)
-            .map(|parameter| parameter.clone_ref(py))
-            .ok_or_else(|| PyKeyError::new_err(node.name.clone()))?;
-
-        let default = parameter.getattr(py, "default")?;
-        let default = if default.is(import_inspect(py)?.getattr("Parameter")?.getattr("empty")?) {
-            None
-        } else {
-            Some(default.as_ref(py))
-        };
+            .ok_or_else(|| PyKeyError::new_err(node.name.clone()))?
+            .default
+            .as_ref()
+            .map(|default| default.clone_ref(py).into_ref(py));
 
         let typing = import_typing(py)?;
         if !typing
@@ -324,7 +544,8 @@ impl Visitor for ParameterVisitor {
 
             let callback = arg.getattr("callback")?;
             if !callback.is_none() {
-                return Ok(Some(Injected::new_callback(py, callback)));
+                let options = CallbackOptions::from_descriptor(py, arg)?;
+                return Ok(Some(Injected::new_callback(py, callback, options)));
             }
 
             let type_ = arg.getattr("type")?;
@@ -344,8 +565,6 @@ impl Visitor for ParameterVisitor {
             return Ok(vec![]);
         }
 
-        let positional_only = import_inspect(py)?.getattr("Parameter")?.getattr("POSITIONAL_ONLY")?;
-
         signature
             .unwrap()
             .into_iter()
@@ -354,7 +573,7 @@ impl Visitor for ParameterVisitor {
                     .or_else(|| _accept::<Annotation, Self>(py, callback.clone(), &name))
                     .transpose()?
                 {
-                    if value.getattr(py, "kind")?.is(positional_only) {
+                    if value.positional_only {
                         return Err(PyValueError::new_err(
                             "Injected positional only arguments are not supported",
                         ));
@@ -377,7 +596,8 @@ impl Visitor for ParameterVisitor {
 
         let callback = default.getattr(py, "callback")?;
         if !callback.is_none(py) {
-            return Ok(Some(Injected::new_callback(py, callback.as_ref(py))));
+            let options = CallbackOptions::from_descriptor(py, default.as_ref(py))?;
+            return Ok(Some(Injected::new_callback(py, callback.as_ref(py), options)));
         };
 
         let type_ = default.getattr(py, "type")?;
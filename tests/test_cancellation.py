@@ -0,0 +1,137 @@
+# -*- coding: utf-8 -*-
+# cython: language_level=3
+# BSD 3-Clause License
+#
+# Copyright (c) 2020-2022, Faster Speeding
+# All rights reserved.
+#
+# Redistribution and use in source and binary forms, with or without
+# modification, are permitted provided that the following conditions are met:
+#
+# * Redistributions of source code must retain the above copyright notice, this
+#   list of conditions and the following disclaimer.
+#
+# * Redistributions in binary form must reproduce the above copyright notice,
+#   this list of conditions and the following disclaimer in the documentation
+#   and/or other materials provided with the distribution.
+#
+# * Neither the name of the copyright holder nor the names of its
+#   contributors may be used to endorse or promote products derived from
+#   this software without specific prior written permission.
+#
+# THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+# AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+# IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+# DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+# FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+# DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+# SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+# CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+# OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+# OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+from __future__ import annotations
+
+import collections.abc as collections
+
+import alluka
+import anyio
+import pytest
+
+import alluka_rust
+
+
+@pytest.mark.anyio()
+async def test_cancelling_call_cancels_in_flight_dependency() -> None:
+    started = anyio.Event()
+    dependency_cancelled = anyio.Event()
+
+    async def dependency() -> None:
+        started.set()
+        try:
+            await anyio.sleep_forever()
+
+        except anyio.get_cancelled_exc_class():
+            dependency_cancelled.set()
+            raise
+
+    async def callback(value: None = alluka.inject(callback=dependency)) -> None:
+        pytest.fail("The callback shouldn't be called")
+
+    client = alluka_rust.Client()
+
+    async with anyio.create_task_group() as task_group:
+        task_group.start_soon(client.call_with_async_di, callback)
+        await started.wait()
+        task_group.cancel_scope.cancel()
+
+    with anyio.fail_after(1):
+        await dependency_cancelled.wait()
+
+
+@pytest.mark.anyio()
+async def test_cancelling_call_closes_unused_generator_dependencies() -> None:
+    started = anyio.Event()
+    closed = False
+
+    def generator() -> collections.Generator[None, None, None]:
+        nonlocal closed
+        try:
+            yield
+
+        finally:
+            closed = True
+
+    def resource() -> collections.Generator[None, None, None]:
+        value = generator()
+        next(value)
+        return value
+
+    async def blocker() -> None:
+        started.set()
+        await anyio.sleep_forever()
+
+    async def callback(
+        value: collections.Generator[None, None, None] = alluka.inject(
+            callback=resource
+        ),
+        other: None = alluka.inject(callback=blocker),
+    ) -> None:
+        pytest.fail("The callback shouldn't be called")
+
+    client = alluka_rust.Client()
+
+    async with anyio.create_task_group() as task_group:
+        task_group.start_soon(client.call_with_async_di, callback)
+        await started.wait()
+        task_group.cancel_scope.cancel()
+
+    assert closed is True
+
+
+@pytest.mark.anyio()
+async def test_cancelled_dependency_isnt_retried() -> None:
+    started = anyio.Event()
+    calls = 0
+
+    async def dependency() -> None:
+        nonlocal calls
+        calls += 1
+        started.set()
+        await anyio.sleep_forever()
+
+    async def callback(
+        value: None = alluka_rust.inject(
+            callback=dependency, retry=alluka_rust.RetryPolicy(3, on=(BaseException,))
+        )
+    ) -> None:
+        pytest.fail("The callback shouldn't be called")
+
+    client = alluka_rust.Client()
+
+    async with anyio.create_task_group() as task_group:
+        task_group.start_soon(client.call_with_async_di, callback)
+        await started.wait()
+        task_group.cancel_scope.cancel()
+
+    await anyio.sleep(0.1)
+    assert calls == 1
@@ -0,0 +1,126 @@
+# -*- coding: utf-8 -*-
+# cython: language_level=3
+# BSD 3-Clause License
+#
+# Copyright (c) 2020-2022, Faster Speeding
+# All rights reserved.
+#
+# Redistribution and use in source and binary forms, with or without
+# modification, are permitted provided that the following conditions are met:
+#
+# * Redistributions of source code must retain the above copyright notice, this
+#   list of conditions and the following disclaimer.
+#
+# * Redistributions in binary form must reproduce the above copyright notice,
+#   this list of conditions and the following disclaimer in the documentation
+#   and/or other materials provided with the distribution.
+#
+# * Neither the name of the copyright holder nor the names of its
+#   contributors may be used to endorse or promote products derived from
+#   this software without specific prior written permission.
+#
+# THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+# AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+# IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+# DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+# FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+# DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+# SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+# CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+# OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+# OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+from __future__ import annotations
+
+import concurrent.futures
+import threading
+
+import alluka
+
+import alluka_rust
+
+_THREADS = 8
+_TYPES_PER_THREAD = 100
+
+
+def test_concurrent_registration_from_threads() -> None:
+    client = alluka_rust.Client()
+    types = [
+        [type(f"Type{thread}_{index}", (), {}) for index in range(_TYPES_PER_THREAD)]
+        for thread in range(_THREADS)
+    ]
+    barrier = threading.Barrier(_THREADS)
+
+    def register(thread: int) -> None:
+        barrier.wait()
+        for index, type_ in enumerate(types[thread]):
+            client.set_type_dependency(type_, (thread, index))
+            client.set_callback_override(type_, type_)
+
+        for type_ in types[thread][::2]:
+            client.remove_type_dependency(type_)
+            client.remove_callback_override(type_)
+
+    with concurrent.futures.ThreadPoolExecutor(_THREADS) as executor:
+        for future in [executor.submit(register, thread) for thread in range(_THREADS)]:
+            future.result()
+
+    for thread, thread_types in enumerate(types):
+        for index, type_ in enumerate(thread_types):
+            if index % 2:
+                assert client.get_type_dependency(type_) == (thread, index)
+                assert client.get_callback_override(type_) is type_
+
+            else:
+                assert client.get_type_dependency(type_, default=None) is None
+                assert client.get_callback_override(type_) is None
+
+
+def test_concurrent_calls_see_registrations_from_threads() -> None:
+    class Dependency:
+        ...
+
+    client = alluka_rust.Client().set_type_dependency(Dependency, 0)
+    stop = threading.Event()
+
+    def callback(value: int = alluka.inject(type=Dependency)) -> int:
+        return value
+
+    def write() -> None:
+        for value in range(1, 1_000):
+            client.set_type_dependency(Dependency, value)
+
+        stop.set()
+
+    def read() -> list[int]:
+        results = []
+        while not stop.is_set():
+            results.append(client.call_with_di(callback))
+
+        return results
+
+    with concurrent.futures.ThreadPoolExecutor(_THREADS) as executor:
+        readers = [executor.submit(read) for _ in range(_THREADS - 1)]
+        executor.submit(write).result()
+        for reader in readers:
+            assert all(0 <= value < 1_000 for value in reader.result())
+
+    assert client.call_with_di(callback) == 999
+
+
+def test_removed_value_can_reenter_registry_when_dropped() -> None:
+    class Key:
+        ...
+
+    class Other:
+        ...
+
+    client = alluka_rust.Client()
+
+    class Reentrant:
+        def __del__(self) -> None:
+            client.set_type_dependency(Other, "reentered")
+
+    client.set_type_dependency(Key, Reentrant())
+    client.remove_type_dependency(Key)
+
+    assert client.get_type_dependency(Other) == "reentered"